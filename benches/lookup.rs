@@ -0,0 +1,97 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use postgres_secrets::pgpass::*;
+
+/// A generated file, similar to one on a bastion host: many concrete entries,
+/// with a few wildcard entries at the end.
+fn generated(hosts: usize, databases: usize) -> PgPass {
+    let mut pgpass = PgPass::default();
+    for host in 0..hosts {
+        for database in 0..databases {
+            pgpass.add(
+                CredentialPattern::default()
+                    .hostname(format!("db-{}.internal", host))
+                    .unwrap()
+                    .port(5432)
+                    .unwrap()
+                    .database(format!("database_{}", database))
+                    .unwrap()
+                    .username("service")
+                    .unwrap()
+                    .password(format!("password-{}-{}", host, database))
+                    .unwrap(),
+            );
+        }
+    }
+    pgpass.add(
+        CredentialPattern::default()
+            .username("readonly")
+            .unwrap()
+            .password("readonly")
+            .unwrap(),
+    );
+    pgpass
+}
+
+fn lookup(c: &mut Criterion) {
+    let pgpass = generated(100, 50);
+    let index = PgPassIndex::new(pgpass.clone());
+
+    let queries = [
+        (
+            "first",
+            CredentialQuery::default()
+                .hostname("db-0.internal")
+                .unwrap()
+                .database("database_0")
+                .unwrap()
+                .username("service")
+                .unwrap(),
+        ),
+        (
+            "last",
+            CredentialQuery::default()
+                .hostname("db-99.internal")
+                .unwrap()
+                .database("database_49")
+                .unwrap()
+                .username("service")
+                .unwrap(),
+        ),
+        (
+            "wildcard",
+            CredentialQuery::default()
+                .hostname("db-50.internal")
+                .unwrap()
+                .database("database_25")
+                .unwrap()
+                .username("readonly")
+                .unwrap(),
+        ),
+        (
+            "missing",
+            CredentialQuery::default()
+                .hostname("db-1000.internal")
+                .unwrap()
+                .username("service")
+                .unwrap(),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("lookup");
+    for (name, query) in queries.iter() {
+        group.bench_function(format!("find/{}", name), |b| {
+            b.iter(|| pgpass.find(black_box(query)))
+        });
+        group.bench_function(format!("index/{}", name), |b| {
+            b.iter(|| index.find(black_box(query)))
+        });
+    }
+    group.finish();
+
+    c.bench_function("index/build", |b| {
+        b.iter(|| PgPassIndex::new(black_box(pgpass.clone())))
+    });
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
@@ -0,0 +1,76 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use postgres_secrets::pgpass::*;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counts allocations, so the parsers can be compared by how many they make as
+/// well as by time.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// A generated file with many entries, a few of which contain escapes.
+fn generated(entries: usize) -> String {
+    let mut s = String::from("# Generated\n");
+    for i in 0..entries {
+        if i % 10 == 0 {
+            s.push_str(&format!(
+                "db-{}.internal:5432:database_{}:service:pass\\:word-{}\n",
+                i, i, i
+            ));
+        } else {
+            s.push_str(&format!(
+                "db-{}.internal:5432:database_{}:service:password-{}\n",
+                i, i, i
+            ));
+        }
+    }
+    s
+}
+
+fn allocations<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn parse(c: &mut Criterion) {
+    let s = generated(5000);
+
+    println!(
+        "allocations for 5000 entries: owned {}, borrowed {}",
+        allocations(|| s.parse::<PgPass>().unwrap()),
+        allocations(|| PgPass::parse_borrowed(&s).unwrap()),
+    );
+
+    let mut group = c.benchmark_group("parse");
+    group.bench_function("owned", |b| {
+        b.iter(|| black_box(&s).parse::<PgPass>().unwrap())
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| PgPass::parse_borrowed(black_box(&s)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
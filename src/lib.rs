@@ -20,12 +20,18 @@ pub mod pgpass;
 pub use pgpass::PgPass;
 #[doc(hidden)]
 pub mod doctest_utils;
+#[cfg(any(feature = "deadpool", feature = "r2d2", feature = "bb8"))]
+pub mod pool;
+pub mod source;
+#[cfg(test)]
+mod test_utils;
 
 pub const DEFAULT_PORT: u16 = 5432;
 
 /// Credentials for accessing a Postgres database.
 /// This can be used either by accessing it's fields directly, or by converting
-/// it into a [`postgres::Config`].
+/// it into a `postgres::Config` (with the `postgres` feature, which is enabled by
+/// default).
 ///
 /// ```
 /// # use postgres_secrets::pgpass::*;
@@ -58,6 +64,7 @@ pub struct Credentials {
     pub username: String,
     pub password: String,
 }
+#[cfg(feature = "postgres")]
 impl From<Credentials> for postgres::Config {
     fn from(value: Credentials) -> Self {
         let mut config = Self::new();
@@ -70,6 +77,34 @@ impl From<Credentials> for postgres::Config {
         config
     }
 }
+#[cfg(feature = "tokio-postgres")]
+impl From<Credentials> for tokio_postgres::Config {
+    fn from(value: Credentials) -> Self {
+        let mut config = Self::new();
+        config
+            .host(&value.hostname)
+            .port(value.port.get())
+            .dbname(&value.database)
+            .user(&value.username)
+            .password(&value.password);
+        config
+    }
+}
+#[cfg(feature = "sqlx")]
+impl From<Credentials> for sqlx_postgres::PgConnectOptions {
+    /// Options which are not part of the credentials (such as the SSL mode) are
+    /// taken from the environment, as in [`new_without_pgpass`][a].
+    ///
+    /// [a]: sqlx_postgres::PgConnectOptions::new_without_pgpass
+    fn from(value: Credentials) -> Self {
+        Self::new_without_pgpass()
+            .host(&value.hostname)
+            .port(value.port.get())
+            .database(&value.database)
+            .username(&value.username)
+            .password(&value.password)
+    }
+}
 impl Debug for Credentials {
     // Hand-rolled to censor passwords
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -0,0 +1,102 @@
+//! Loading pgpass files without blocking an async runtime. Requires the `async`
+//! feature.
+
+use std::path::{Path, PathBuf};
+
+use tokio::{fs, io::AsyncRead};
+
+use super::{Candidate, LoadError, LoadOptions, PgPass};
+
+impl PgPass {
+    /// The async equivalent of [`load`][PgPass::load].
+    pub async fn load_async() -> Result<Self, LoadError> {
+        Self::load_async_with(&LoadOptions::default()).await
+    }
+    /// The async equivalent of [`load_with`][PgPass::load_with].
+    pub async fn load_async_with(options: &LoadOptions) -> Result<Self, LoadError> {
+        let Some(path) = Self::locate_async().await else {
+            return Err(LoadError::CouldNotLocate);
+        };
+        Self::open_async_with(path, options).await
+    }
+    /// The async equivalent of [`read`][PgPass::read].
+    pub async fn read_async<F: AsyncRead + Unpin>(f: F) -> Result<Self, LoadError> {
+        Self::read_async_with(f, &LoadOptions::default()).await
+    }
+    /// The async equivalent of [`read_with`][PgPass::read_with].
+    pub async fn read_async_with<F: AsyncRead + Unpin>(
+        f: F,
+        options: &LoadOptions,
+    ) -> Result<Self, LoadError> {
+        let contents = options.read_limited_async(f).await?;
+        Self::parse_contents(&contents, options)
+    }
+    /// The async equivalent of [`open`][PgPass::open].
+    pub async fn open_async<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        Self::open_async_with(path, &LoadOptions::default()).await
+    }
+    /// The async equivalent of [`open_with`][PgPass::open_with].
+    pub async fn open_async_with<P: AsRef<Path>>(
+        path: P,
+        options: &LoadOptions,
+    ) -> Result<Self, LoadError> {
+        let f = fs::File::open(path.as_ref()).await?;
+        Self::read_async_with(f, options).await
+    }
+    /// The async equivalent of [`locate`][PgPass::locate]. Checking whether the
+    /// default path exists is done with [`tokio::fs`].
+    pub async fn locate_async() -> Option<PathBuf> {
+        match Self::locate_candidate()? {
+            Candidate::Environment(path) => Some(path),
+            Candidate::Default(path) => {
+                let is_file = fs::metadata(&path)
+                    .await
+                    .is_ok_and(|metadata| metadata.is_file());
+                Self::located_default(path, is_file)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pgpass::CredentialQuery;
+
+    #[tokio::test]
+    async fn read() -> anyhow::Result<()> {
+        let s = "# Comment\nlocalhost:*:*:*:password\n";
+        let pgpass = PgPass::read_async(s.as_bytes()).await?;
+        assert_eq!(pgpass, s.parse()?);
+        assert_eq!(pgpass.line(0), Some(2));
+
+        let options = LoadOptions::default().with_max_file_size(Some(16));
+        assert!(matches!(
+            PgPass::read_async_with(s.as_bytes(), &options).await,
+            Err(LoadError::FileTooLarge { limit: 16 })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("pgpass-async-{}", std::process::id()));
+        fs::write(&path, "localhost:*:*:*:password\n").await?;
+        let pgpass = PgPass::open_async(&path).await;
+        fs::remove_file(&path).await?;
+
+        let query = CredentialQuery::default()
+            .hostname("localhost")?
+            .database("database")?
+            .username("username")?;
+        assert_eq!(pgpass?.find(&query)?.unwrap().password, "password");
+
+        assert!(matches!(
+            PgPass::open_async(&path).await,
+            Err(LoadError::Io(_))
+        ));
+
+        Ok(())
+    }
+}
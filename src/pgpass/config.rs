@@ -0,0 +1,513 @@
+//! Looking up the missing password of an existing connection config. See
+//! [`PgPass::apply_to`] and [`CredentialQuery::per_host`]. Requires the `postgres`
+//! or `tokio-postgres` feature.
+
+use std::{env, net::IpAddr};
+
+#[cfg(feature = "postgres")]
+use postgres::config::Host;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+#[cfg(not(feature = "postgres"))]
+use tokio_postgres::config::Host;
+
+use crate::DEFAULT_PORT;
+
+use super::{pattern::InvalidField, CredentialQuery, FindError, PgPass};
+
+/// A Postgres connection config which a [`CredentialQuery`] can be built from.
+/// This is implemented for `postgres::Config` and `tokio_postgres::Config`, when
+/// the `postgres` and `tokio-postgres` features are enabled respectively. It cannot be implemented
+/// outside of this crate.
+pub trait ConnectionConfig: private::Sealed {
+    fn hosts(&self) -> &[Host];
+    fn hostaddrs(&self) -> &[IpAddr];
+    fn ports(&self) -> &[u16];
+    fn dbname(&self) -> Option<&str>;
+    fn user(&self) -> Option<&str>;
+}
+
+pub(crate) mod private {
+    pub trait Sealed {
+        fn password(&self) -> Option<&[u8]>;
+        fn set_password(&mut self, password: &str);
+    }
+}
+
+macro_rules! impl_connection_config {
+    ($config:ty) => {
+        impl private::Sealed for $config {
+            fn password(&self) -> Option<&[u8]> {
+                self.get_password()
+            }
+            fn set_password(&mut self, password: &str) {
+                self.password(password);
+            }
+        }
+        impl ConnectionConfig for $config {
+            fn hosts(&self) -> &[Host] {
+                self.get_hosts()
+            }
+            fn hostaddrs(&self) -> &[IpAddr] {
+                self.get_hostaddrs()
+            }
+            fn ports(&self) -> &[u16] {
+                self.get_ports()
+            }
+            fn dbname(&self) -> Option<&str> {
+                self.get_dbname()
+            }
+            fn user(&self) -> Option<&str> {
+                self.get_user()
+            }
+        }
+    };
+}
+#[cfg(feature = "postgres")]
+impl_connection_config!(postgres::Config);
+#[cfg(feature = "tokio-postgres")]
+impl_connection_config!(tokio_postgres::Config);
+
+/// An error encountered when building a [`CredentialQuery`] from a
+/// [`ConnectionConfig`].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The config has more than one host, so it does not correspond to a single
+    /// query. Use [`CredentialQuery::per_host`].
+    #[error("The config has {0} hosts; expected exactly 1.")]
+    MultipleHosts(usize),
+    /// There must be one port, or one port for each host.
+    #[error("The config has {ports} ports for {hosts} hosts.")]
+    MismatchedPorts { hosts: usize, ports: usize },
+    #[error("{0}")]
+    InvalidField(#[from] InvalidField),
+}
+
+impl CredentialQuery {
+    /// Build one query for each host of the config, in order. Each host is paired
+    /// with it's port (or the only port, if there is one), and with the database
+    /// and user of the config. Fields which the config does not set are wildcards.
+    ///
+    /// As in `libpq`, if the config has `hostaddr`s but no hosts, they are used
+    /// as the hostnames, and a Unix socket is queried as `localhost`.
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # #[cfg(not(feature = "postgres"))]
+    /// # use tokio_postgres as postgres;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let pgpass: PgPass = "replica.example.com:5433:orders:alice:secret".parse()?;
+    /// let config: postgres::Config =
+    ///     "host=primary.example.com,replica.example.com port=5432,5433 dbname=orders user=alice"
+    ///         .parse()?;
+    ///
+    /// let queries = CredentialQuery::per_host(&config)?;
+    /// assert_eq!(queries.len(), 2);
+    /// let creds = queries.iter().find_map(|query| pgpass.find(query).transpose());
+    /// assert_eq!(creds.unwrap()?.password, "secret");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn per_host<C: ConnectionConfig>(config: &C) -> Result<Vec<Self>, ConfigError> {
+        let hostnames: Vec<Option<String>> = if !config.hosts().is_empty() {
+            config
+                .hosts()
+                .iter()
+                .map(|host| match host {
+                    Host::Tcp(hostname) => Some(hostname.clone()),
+                    #[cfg(unix)]
+                    Host::Unix(_) => Some("localhost".to_string()),
+                })
+                .collect()
+        } else if !config.hostaddrs().is_empty() {
+            config
+                .hostaddrs()
+                .iter()
+                .map(|addr| Some(addr.to_string()))
+                .collect()
+        } else {
+            vec![None]
+        };
+
+        let ports = config.ports();
+        if ports.len() > 1 && ports.len() != hostnames.len() {
+            return Err(ConfigError::MismatchedPorts {
+                hosts: hostnames.len(),
+                ports: ports.len(),
+            });
+        }
+
+        hostnames
+            .into_iter()
+            .enumerate()
+            .map(|(i, hostname)| {
+                let mut query = CredentialQuery::default();
+                if let Some(hostname) = hostname {
+                    query = query.hostname(hostname)?;
+                }
+                if let Some(port) = ports.get(i).or(ports.first()) {
+                    query = query.port(*port)?;
+                }
+                if let Some(database) = config.dbname() {
+                    query = query.database(database)?;
+                }
+                if let Some(username) = config.user() {
+                    query = query.username(username)?;
+                }
+                Ok(query)
+            })
+            .collect()
+    }
+    /// Build a query from a config with at most one host. See
+    /// [`per_host`][CredentialQuery::per_host].
+    fn from_config<C: ConnectionConfig>(config: &C) -> Result<Self, ConfigError> {
+        let mut queries = Self::per_host(config)?;
+        if queries.len() != 1 {
+            return Err(ConfigError::MultipleHosts(queries.len()));
+        }
+        Ok(queries.remove(0))
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl TryFrom<&postgres::Config> for CredentialQuery {
+    type Error = ConfigError;
+
+    fn try_from(value: &postgres::Config) -> Result<Self, Self::Error> {
+        Self::from_config(value)
+    }
+}
+#[cfg(feature = "tokio-postgres")]
+impl TryFrom<&tokio_postgres::Config> for CredentialQuery {
+    type Error = ConfigError;
+
+    fn try_from(value: &tokio_postgres::Config) -> Result<Self, Self::Error> {
+        Self::from_config(value)
+    }
+}
+
+/// Which pattern matched each host of a config. See [`PgPass::apply_to`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ApplyReport {
+    /// Whether the password of the config was set.
+    pub password_set: bool,
+    /// One entry for each host, in order. This is empty if the config already
+    /// had a password, as then the pgpass file is not consulted.
+    pub hosts: Vec<HostReport>,
+}
+
+/// The pattern used for one host of a config.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HostReport {
+    /// The query for this host, after `libpq`'s defaults were applied.
+    pub query: CredentialQuery,
+    /// The position of the matching pattern in the [`PgPass`], if any pattern matched.
+    pub index: Option<usize>,
+    /// The line of the file the matching pattern was read from, if known.
+    pub line: Option<usize>,
+}
+
+/// An error encountered by [`PgPass::apply_to`]. It is safe to log or display
+/// this error; it will not contain passwords.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ApplyError {
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+    #[error("{0}")]
+    Find(#[from] FindError),
+    /// Two hosts of the config matched patterns with different passwords. A
+    /// config only has one password, and sending a host the password of a
+    /// different host would leak it, so none is set.
+    #[error("Hosts {first} and {second} of the config have different passwords.")]
+    ConflictingPasswords { first: usize, second: usize },
+}
+
+impl PgPass {
+    /// Set the password of a config from the pgpass file, if it doesn't already
+    /// have one. This is what `libpq` does when connecting without a password.
+    ///
+    /// Each host of the config is looked up with [`find`][PgPass::find], after
+    /// applying `libpq`'s defaults for any missing fields:
+    /// - The host is `localhost`, which is also used for Unix sockets.
+    /// - The port is the [default port][crate::DEFAULT_PORT].
+    /// - The user is taken from the `PGUSER` environment variable, or else the
+    ///   name of the current user (from `USER`, or `USERNAME` on Windows).
+    /// - The database has the same name as the user.
+    ///
+    /// Only the password of the config is modified. Every host which matched
+    /// must agree on the password, otherwise [`ApplyError::ConflictingPasswords`]
+    /// is returned.
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # #[cfg(not(feature = "postgres"))]
+    /// # use tokio_postgres as postgres;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let pgpass: PgPass = "# Production\ndb.example.com:*:alice:alice:secret".parse()?;
+    /// let mut config: postgres::Config = "host=db.example.com user=alice".parse()?;
+    ///
+    /// let report = pgpass.apply_to(&mut config)?;
+    /// assert!(report.password_set);
+    /// assert_eq!(report.hosts[0].line, Some(2));
+    /// assert_eq!(config.get_password(), Some(&b"secret"[..]));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn apply_to<C: ConnectionConfig>(&self, config: &mut C) -> Result<ApplyReport, ApplyError> {
+        self.apply_to_with_env(config, |name| env::var(name).ok())
+    }
+    fn apply_to_with_env<C: ConnectionConfig>(
+        &self,
+        config: &mut C,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<ApplyReport, ApplyError> {
+        if config.password().is_some() {
+            return Ok(ApplyReport::default());
+        }
+
+        let username = config
+            .user()
+            .map(str::to_string)
+            .or_else(|| env("PGUSER"))
+            .or_else(|| env(if cfg!(windows) { "USERNAME" } else { "USER" }));
+        let database = config
+            .dbname()
+            .map(str::to_string)
+            .or_else(|| username.clone());
+
+        let mut hosts = Vec::new();
+        let mut password: Option<(usize, String)> = None;
+        for (i, query) in CredentialQuery::per_host(config)?.into_iter().enumerate() {
+            let query = Self::with_libpq_defaults(query, username.as_deref(), database.as_deref())
+                .map_err(ConfigError::from)?;
+
+            let (index, line) = match self.settings.match_strategy.select(self.find_all(&query)) {
+                Some(m) => {
+                    let creds = m.credentials()?;
+                    match &password {
+                        Some((first, p)) if *p != creds.password => {
+                            return Err(ApplyError::ConflictingPasswords {
+                                first: *first,
+                                second: i,
+                            })
+                        }
+                        Some(_) => (),
+                        None => password = Some((i, creds.password)),
+                    }
+                    (Some(m.index), m.line)
+                }
+                None => (None, None),
+            };
+            hosts.push(HostReport { query, index, line });
+        }
+
+        let password_set = password.is_some();
+        if let Some((_, password)) = password {
+            config.set_password(&password);
+        }
+        Ok(ApplyReport {
+            password_set,
+            hosts,
+        })
+    }
+    fn with_libpq_defaults(
+        mut query: CredentialQuery,
+        username: Option<&str>,
+        database: Option<&str>,
+    ) -> Result<CredentialQuery, InvalidField> {
+        if query.hostname.is_none() {
+            query = query.hostname("localhost")?;
+        }
+        if query.port.is_none() {
+            query = query.port(DEFAULT_PORT)?;
+        }
+        if let (None, Some(username)) = (&query.username, username) {
+            query = query.username(username)?;
+        }
+        if let (None, Some(database)) = (&query.database, database) {
+            query = query.database(database)?;
+        }
+        Ok(query)
+    }
+}
+
+#[cfg(all(test, any(feature = "postgres", feature = "tokio-postgres")))]
+mod test {
+    use super::*;
+    #[cfg(feature = "postgres")]
+    use postgres::Config;
+    #[cfg(not(feature = "postgres"))]
+    use tokio_postgres::Config;
+
+    #[test]
+    fn single_host() -> anyhow::Result<()> {
+        let config: Config = "host=example.com port=123 dbname=database user=username".parse()?;
+        let expected = CredentialQuery::default()
+            .hostname("example.com")?
+            .port(123)?
+            .database("database")?
+            .username("username")?;
+        assert_eq!(CredentialQuery::try_from(&config)?, expected);
+
+        // Unset fields are wildcards
+        let config: Config = "host=example.com".parse()?;
+        assert_eq!(
+            CredentialQuery::try_from(&config)?,
+            CredentialQuery::default().hostname("example.com")?
+        );
+        let config = Config::new();
+        assert_eq!(
+            CredentialQuery::try_from(&config)?,
+            CredentialQuery::default()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_hosts() -> anyhow::Result<()> {
+        let config: Config = "host=a,b,c port=1,2,3 user=username".parse()?;
+        let hosts: Vec<_> = CredentialQuery::per_host(&config)?
+            .into_iter()
+            .map(|query| (query.hostname.unwrap(), query.port.unwrap().get()))
+            .collect();
+        assert_eq!(
+            hosts,
+            vec![
+                ("a".to_string(), 1),
+                ("b".to_string(), 2),
+                ("c".to_string(), 3)
+            ]
+        );
+        assert_eq!(
+            CredentialQuery::try_from(&config),
+            Err(ConfigError::MultipleHosts(3))
+        );
+
+        // A single port is shared
+        let config: Config = "host=a,b port=1".parse()?;
+        let ports: Vec<_> = CredentialQuery::per_host(&config)?
+            .into_iter()
+            .map(|query| query.port.unwrap().get())
+            .collect();
+        assert_eq!(ports, vec![1, 1]);
+
+        let mut config = Config::new();
+        config.host("a").host("b").port(1).port(2).port(3);
+        assert_eq!(
+            CredentialQuery::per_host(&config),
+            Err(ConfigError::MismatchedPorts { hosts: 2, ports: 3 })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn hostaddr_and_sockets() -> anyhow::Result<()> {
+        let config: Config = "hostaddr=127.0.0.1".parse()?;
+        assert_eq!(
+            CredentialQuery::try_from(&config)?,
+            CredentialQuery::default().hostname("127.0.0.1")?
+        );
+
+        #[cfg(unix)]
+        {
+            let config: Config = "host=/var/run/postgresql".parse()?;
+            assert_eq!(
+                CredentialQuery::try_from(&config)?,
+                CredentialQuery::default().hostname("localhost")?
+            );
+        }
+
+        Ok(())
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn apply_to_sets_missing_password() -> anyhow::Result<()> {
+        let s = "a:5432:database:username:one\n\
+            b:5433:database:username:two\n";
+        let pgpass: PgPass = s.parse()?;
+
+        let mut config: Config =
+            "host=a,b,c port=5432,5433,5434 dbname=database user=username".parse()?;
+        assert_eq!(
+            pgpass.apply_to_with_env(&mut config, no_env),
+            Err(ApplyError::ConflictingPasswords {
+                first: 0,
+                second: 1
+            })
+        );
+        assert_eq!(config.get_password(), None);
+
+        let mut config: Config = "host=c,b port=5432,5433 dbname=database user=username".parse()?;
+        let report = pgpass.apply_to_with_env(&mut config, no_env)?;
+        assert!(report.password_set);
+        assert_eq!(config.get_password(), Some(&b"two"[..]));
+        let matched: Vec<_> = report
+            .hosts
+            .iter()
+            .map(|host| (host.index, host.line))
+            .collect();
+        assert_eq!(matched, vec![(None, None), (Some(1), Some(2))]);
+
+        // An existing password is kept
+        let mut config: Config = "host=a user=username password=mine".parse()?;
+        let report = pgpass.apply_to_with_env(&mut config, no_env)?;
+        assert_eq!(report, ApplyReport::default());
+        assert_eq!(config.get_password(), Some(&b"mine"[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_to_uses_libpq_defaults() -> anyhow::Result<()> {
+        let pgpass: PgPass = "localhost:5432:alice:alice:secret".parse()?;
+
+        // The database defaults to the user
+        let mut config: Config = "user=alice".parse()?;
+        let report = pgpass.apply_to_with_env(&mut config, no_env)?;
+        assert_eq!(
+            report.hosts[0].query,
+            CredentialQuery::default()
+                .hostname("localhost")?
+                .port(5432)?
+                .database("alice")?
+                .username("alice")?
+        );
+        assert_eq!(config.get_password(), Some(&b"secret"[..]));
+
+        // The user defaults to PGUSER, then to the current user
+        let env = |name: &str| (name == "PGUSER").then(|| "alice".to_string());
+        let mut config = Config::new();
+        assert!(pgpass.apply_to_with_env(&mut config, env)?.password_set);
+        let env = |name: &str| (name == "USER" || name == "USERNAME").then(|| "alice".to_string());
+        let mut config = Config::new();
+        assert!(pgpass.apply_to_with_env(&mut config, env)?.password_set);
+
+        // No match on a different port
+        let mut config: Config = "user=alice port=5433".parse()?;
+        let report = pgpass.apply_to_with_env(&mut config, no_env)?;
+        assert!(!report.password_set);
+        assert_eq!(config.get_password(), None);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio-postgres")]
+    #[test]
+    fn tokio_postgres() -> anyhow::Result<()> {
+        let config: tokio_postgres::Config = "host=example.com dbname=database".parse()?;
+        assert_eq!(
+            CredentialQuery::try_from(&config)?,
+            CredentialQuery::default()
+                .hostname("example.com")?
+                .database("database")?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+//! Encrypted pgpass files, using [age](https://age-encryption.org). Requires the
+//! `encryption` feature.
+//!
+//! The file is an ordinary age file, wrapping the text of a pgpass file. It can
+//! be encrypted to X25519 keys, such as those generated by `age-keygen`, or with
+//! a passphrase. Files produced by the `age` command line tool can be read, and
+//! vice versa.
+//!
+//! The decrypted contents are wiped from memory once they have been parsed.
+//!
+//! ```no_run
+//! # use postgres_secrets::{PgPass, pgpass::encrypted::*};
+//! # fn main() -> anyhow::Result<()> {
+//! let identity = Identity::open("/home/alice/.config/pgpass/key.txt")?;
+//! let pgpass = PgPass::open_encrypted("/home/alice/.pgpass.age", &identity)?;
+//!
+//! pgpass.save_encrypted("/home/alice/.pgpass.age.new", &identity.to_recipients())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    io::{self, BufReader, ErrorKind, Read, Write},
+    iter,
+    path::Path,
+    str::FromStr,
+};
+
+use age::{
+    scrypt,
+    secrecy::{ExposeSecret, SecretString},
+    x25519,
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::{LoadError, LoadOptions, PgPass};
+
+/// A key which can decrypt a file.
+#[derive(Clone)]
+pub enum Identity {
+    /// An age X25519 secret key, beginning with `AGE-SECRET-KEY-1`.
+    X25519(x25519::Identity),
+    /// A passphrase. The key is derived from it with `scrypt`.
+    Passphrase(SecretString),
+}
+impl Identity {
+    /// Generate a new X25519 key.
+    pub fn generate() -> Self {
+        Self::X25519(x25519::Identity::generate())
+    }
+    pub fn passphrase(passphrase: impl Into<String>) -> Self {
+        Self::Passphrase(SecretString::from(passphrase.into()))
+    }
+    /// Read an X25519 key from a file in the format written by `age-keygen`.
+    /// Comments and blank lines are skipped, and the first key is used.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, EncryptionError> {
+        let contents = Zeroizing::new(fs::read_to_string(path)?);
+        contents
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or(EncryptionError::NoIdentity)?
+            .parse()
+    }
+    /// The recipients which this identity can decrypt files for.
+    pub fn to_recipients(&self) -> Recipients {
+        match self {
+            Self::X25519(identity) => Recipients::X25519(vec![identity.to_public()]),
+            Self::Passphrase(passphrase) => Recipients::Passphrase(passphrase.clone()),
+        }
+    }
+    /// The secret key, for saving with [`Identity::open`]. Returns `None` for
+    /// passphrases.
+    pub fn to_secret_key(&self) -> Option<Zeroizing<String>> {
+        match self {
+            Self::X25519(identity) => Some(Zeroizing::new(
+                identity.to_string().expose_secret().to_string(),
+            )),
+            Self::Passphrase(_) => None,
+        }
+    }
+}
+impl FromStr for Identity {
+    type Err = EncryptionError;
+
+    /// Parse an X25519 secret key.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(Self::X25519)
+            .map_err(EncryptionError::InvalidIdentity)
+    }
+}
+impl Debug for Identity {
+    // Hand-rolled to censor keys
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::X25519(identity) => f
+                .debug_tuple("X25519")
+                .field(&identity.to_public())
+                .finish(),
+            Self::Passphrase(_) => f.debug_tuple("Passphrase").field(&"********").finish(),
+        }
+    }
+}
+
+/// Who a file is encrypted for. age can't encrypt a file for both keys and a
+/// passphrase.
+#[derive(Clone, Debug)]
+pub enum Recipients {
+    /// Anyone with one of these keys may decrypt the file.
+    X25519(Vec<x25519::Recipient>),
+    /// Anyone with this passphrase may decrypt the file.
+    Passphrase(SecretString),
+}
+impl Recipients {
+    pub fn passphrase(passphrase: impl Into<String>) -> Self {
+        Self::Passphrase(SecretString::from(passphrase.into()))
+    }
+    fn encryptor(&self) -> Result<age::Encryptor, EncryptionError> {
+        match self {
+            Self::X25519(recipients) => {
+                if recipients.is_empty() {
+                    return Err(EncryptionError::NoRecipients);
+                }
+                age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                    .map_err(EncryptionError::Encrypt)
+            }
+            Self::Passphrase(passphrase) => {
+                Ok(age::Encryptor::with_user_passphrase(passphrase.clone()))
+            }
+        }
+    }
+}
+impl FromStr for Recipients {
+    type Err = EncryptionError;
+
+    /// Parse X25519 public keys, beginning with `age1`, separated by whitespace.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_whitespace()
+            .map(|key| key.parse().map_err(EncryptionError::InvalidRecipient))
+            .collect::<Result<_, _>>()
+            .map(Self::X25519)
+    }
+}
+
+/// An error encountered while encrypting a pgpass file, or reading a key. It is
+/// safe to log or display this error; it will not contain passwords or keys.
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid age identity: {0}.")]
+    InvalidIdentity(&'static str),
+    #[error("Invalid age recipient: {0}.")]
+    InvalidRecipient(&'static str),
+    /// A key file contained no keys.
+    #[error("The key file contains no identities.")]
+    NoIdentity,
+    #[error("A file must be encrypted for at least one recipient.")]
+    NoRecipients,
+    #[error("Failed to encrypt the file: {0}")]
+    Encrypt(age::EncryptError),
+}
+
+impl PgPass {
+    /// Load credentials from the encrypted file at the given path.
+    ///
+    /// The default [`LoadOptions`] are used; see
+    /// [`read_encrypted_with`][PgPass::read_encrypted_with].
+    pub fn open_encrypted<P: AsRef<Path>>(path: P, identity: &Identity) -> Result<Self, LoadError> {
+        Self::open_encrypted_with(path, identity, &LoadOptions::default())
+    }
+    /// Load credentials from the encrypted file at the given path, enforcing the
+    /// given limits.
+    pub fn open_encrypted_with<P: AsRef<Path>>(
+        path: P,
+        identity: &Identity,
+        options: &LoadOptions,
+    ) -> Result<Self, LoadError> {
+        let f = File::open(path.as_ref())?;
+        Self::read_encrypted_with(f, identity, options)
+    }
+    /// Load credentials from an encrypted file, enforcing the given limits.
+    /// The limits apply to the decrypted contents.
+    pub fn read_encrypted_with<F: Read>(
+        f: F,
+        identity: &Identity,
+        options: &LoadOptions,
+    ) -> Result<Self, LoadError> {
+        let decryptor = age::Decryptor::new_buffered(BufReader::new(f))?;
+        let reader = match identity {
+            Identity::X25519(identity) => decryptor.decrypt(iter::once(identity as _)),
+            Identity::Passphrase(passphrase) => {
+                let identity = scrypt::Identity::new(passphrase.clone());
+                decryptor.decrypt(iter::once(&identity as _))
+            }
+        }?;
+        let contents = read_zeroizing(reader, options)?;
+        Self::parse_contents(&contents, options)
+    }
+    /// Encrypt the patterns and write them to a file.
+    pub fn save_encrypted_into<F: Write>(
+        &self,
+        f: F,
+        recipients: &Recipients,
+    ) -> Result<(), EncryptionError> {
+        self.write_encrypted(recipients.encryptor()?, f)
+    }
+    /// Encrypt the credentials and save them to a file at the given path. As
+    /// with [`save`][PgPass::save], the file must not already exist.
+    pub fn save_encrypted<P: AsRef<Path>>(
+        &self,
+        path: P,
+        recipients: &Recipients,
+    ) -> Result<(), EncryptionError> {
+        // Check the recipients before creating the file
+        let encryptor = recipients.encryptor()?;
+        let f = File::create_new(path.as_ref())?;
+        self.write_encrypted(encryptor, f)
+    }
+    fn write_encrypted<F: Write>(
+        &self,
+        encryptor: age::Encryptor,
+        f: F,
+    ) -> Result<(), EncryptionError> {
+        let mut writer = encryptor.wrap_output(f)?;
+        self.save_into(&mut writer)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Read the whole of a decrypted file, as [`LoadOptions::read_limited`] does,
+/// into a buffer which is wiped when dropped. The buffer is grown by hand, so
+/// that no unwiped copies are left behind when it is reallocated.
+fn read_zeroizing<R: Read>(r: R, options: &LoadOptions) -> Result<Zeroizing<Vec<u8>>, LoadError> {
+    let mut r = r.take(options.read_budget());
+    let mut contents = Zeroizing::new(Vec::with_capacity(8192));
+    let mut chunk = Zeroizing::new([0; 8192]);
+    loop {
+        let n = match r.read(&mut *chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if contents.len() + n > contents.capacity() {
+            let mut grown = Zeroizing::new(Vec::with_capacity(2 * (contents.len() + n)));
+            grown.extend_from_slice(&contents);
+            contents = grown;
+        }
+        contents.extend_from_slice(&chunk[..n]);
+    }
+    options.check_read(&mut contents)?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::pgpass::CredentialQuery;
+
+    const PGPASS: &str = "example.com:*:database:username:secret\n*:*:*:*:fallback\n";
+
+    fn query() -> CredentialQuery {
+        CredentialQuery::default()
+            .hostname("example.com")
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username("username")
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let identity = Identity::generate();
+        let pgpass: PgPass = PGPASS.parse()?;
+
+        let mut encrypted = Vec::new();
+        pgpass.save_encrypted_into(&mut encrypted, &identity.to_recipients())?;
+        assert!(!String::from_utf8_lossy(&encrypted).contains("secret"));
+
+        let decrypted =
+            PgPass::read_encrypted_with(&encrypted[..], &identity, &LoadOptions::default())?;
+        assert_eq!(decrypted, pgpass);
+        assert_eq!(decrypted.find(&query())?.unwrap().password, "secret");
+
+        // Other keys can't decrypt it
+        assert!(matches!(
+            PgPass::read_encrypted_with(
+                &encrypted[..],
+                &Identity::generate(),
+                &LoadOptions::default()
+            ),
+            Err(LoadError::Decrypt(age::DecryptError::NoMatchingKeys))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn passphrase() -> anyhow::Result<()> {
+        // Encrypted with a low work factor, so that the test is fast
+        let mut recipient = scrypt::Recipient::new(SecretString::from("hunter2"));
+        recipient.set_work_factor(2);
+        let encryptor = age::Encryptor::with_recipients(iter::once(&recipient as _))?;
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(PGPASS.as_bytes())?;
+        writer.finish()?;
+
+        let options = LoadOptions::default();
+        let identity = Identity::passphrase("hunter2");
+        let pgpass = PgPass::read_encrypted_with(&encrypted[..], &identity, &options)?;
+        assert_eq!(pgpass, PGPASS.parse()?);
+
+        let identity = Identity::passphrase("hunter3");
+        assert!(PgPass::read_encrypted_with(&encrypted[..], &identity, &options).is_err());
+        assert!(!format!("{:?}", identity).contains("hunter3"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn limits_apply_to_plaintext() -> anyhow::Result<()> {
+        let identity = Identity::generate();
+        let pgpass: PgPass = PGPASS.repeat(1000).parse()?;
+        let mut encrypted = Vec::new();
+        pgpass.save_encrypted_into(&mut encrypted, &identity.to_recipients())?;
+
+        let decrypted =
+            PgPass::read_encrypted_with(&encrypted[..], &identity, &LoadOptions::default())?;
+        assert_eq!(decrypted, pgpass);
+
+        let options = LoadOptions::default().with_max_file_size(Some(1024));
+        assert!(matches!(
+            PgPass::read_encrypted_with(&encrypted[..], &identity, &options),
+            Err(LoadError::FileTooLarge { limit: 1024 })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn keys() -> anyhow::Result<()> {
+        let identity = Identity::generate();
+        let key = identity.to_secret_key().unwrap();
+        let file = crate::test_utils::TempFile::new(
+            "age-key",
+            &format!("# created: today\n# public key: ...\n{}\n", *key),
+        );
+        let opened = Identity::open(&file.0)?;
+        assert_eq!(opened.to_secret_key(), Some(key));
+        assert!(!format!("{:?}", opened).contains("AGE-SECRET-KEY"));
+
+        assert!(matches!(
+            "AGE-SECRET-KEY-1NOTAKEY".parse::<Identity>(),
+            Err(EncryptionError::InvalidIdentity(_))
+        ));
+        let recipients: Recipients =
+            "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p".parse()?;
+        assert!(matches!(recipients, Recipients::X25519(r) if r.len() == 1));
+        assert!(matches!(
+            PgPass::default()
+                .save_encrypted_into(Cursor::new(Vec::new()), &Recipients::X25519(Vec::new())),
+            Err(EncryptionError::NoRecipients)
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+//! Encrypting only the password field of a pgpass file. Requires the
+//! `field-encryption` feature.
+//!
+//! An encrypted password is stored as a token, `enc:v1:` followed by the
+//! base64 encoded nonce and ciphertext. Passwords are encrypted with
+//! XChaCha20-Poly1305, using a 256 bit [`FieldKey`]. Hostnames, databases and
+//! usernames are left readable, so that the file can still be reviewed and
+//! diffed. In the file, the colons of the token are escaped like any other:
+//!
+//! ```text
+//! db.example.com:5432:orders:alice:enc\:v1\:mV2Fh...
+//! ```
+//!
+//! Once a key has been set with [`PgPass::set_field_key`],
+//! [`find`][PgPass::find] and [`Match::credentials`][super::matching::Match::credentials]
+//! decrypt the password of the pattern that matched, and only that pattern.
+//! Passwords which aren't tokens are used as they are, so a file may mix
+//! encrypted and plaintext passwords. Streaming lookups (see [`stream`][super::stream])
+//! don't decrypt passwords.
+//!
+//! A token is never returned as a password. If no key is set, looking up a
+//! pattern with an encrypted password fails with [`FieldError::NoKey`]. This
+//! also applies to a plaintext password which happens to start with `enc:v1:`;
+//! encrypt such a password with [`FieldKey::encrypt`] to store it.
+//!
+//! The token is not bound to the other fields of it's pattern, so that they
+//! can be edited without re-encrypting it.
+//!
+//! ```
+//! # use postgres_secrets::pgpass::{*, encrypted_field::*};
+//! # fn main() -> anyhow::Result<()> {
+//! let key = FieldKey::generate();
+//! let token = key.encrypt("secret");
+//! let mut pgpass = PgPass::default().with(
+//!     CredentialPattern::default()
+//!         .hostname("db.example.com")?
+//!         .password(&token)?,
+//! );
+//!
+//! let query = CredentialQuery::default()
+//!     .hostname("db.example.com")?
+//!     .database("orders")?
+//!     .username("alice")?;
+//! pgpass.set_field_key(Some(key));
+//! assert_eq!(pgpass.find(&query)?.unwrap().password, "secret");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    cmp::Ordering,
+    env,
+    fmt::Debug,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::Path,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::PgPass;
+use crate::Credentials;
+
+/// The prefix of an encrypted password.
+pub const TOKEN_PREFIX: &str = "enc:v1:";
+/// The environment variable [`FieldKey::from_env`] reads the key from.
+pub const KEY_VARIABLE: &str = "PGPASS_FIELD_KEY";
+const NONCE_LENGTH: usize = 24;
+
+/// Whether `password` is an encrypted token, rather than a plaintext password.
+pub fn is_encrypted(password: &str) -> bool {
+    password.starts_with(TOKEN_PREFIX)
+}
+
+/// A key for encrypting and decrypting passwords. It is wiped from memory when
+/// dropped.
+#[derive(Clone)]
+pub struct FieldKey(Zeroizing<[u8; 32]>);
+impl FieldKey {
+    /// Generate a new random key.
+    pub fn generate() -> Self {
+        Self(Zeroizing::new(
+            XChaCha20Poly1305::generate_key(&mut OsRng).into(),
+        ))
+    }
+    /// Decode a base64 encoded key.
+    pub fn from_base64(s: &str) -> Result<Self, KeyError> {
+        let bytes = Zeroizing::new(
+            STANDARD
+                .decode(s.trim())
+                .map_err(|_| KeyError::InvalidKey)?,
+        );
+        let key: [u8; 32] = bytes[..].try_into().map_err(|_| KeyError::InvalidKey)?;
+        Ok(Self(Zeroizing::new(key)))
+    }
+    /// Read a base64 encoded key from a file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, KeyError> {
+        let contents = Zeroizing::new(fs::read_to_string(path)?);
+        Self::from_base64(&contents)
+    }
+    /// Read a base64 encoded key from the [`PGPASS_FIELD_KEY`][KEY_VARIABLE]
+    /// environment variable.
+    pub fn from_env() -> Result<Self, KeyError> {
+        let value = Zeroizing::new(env::var(KEY_VARIABLE).map_err(|_| KeyError::NotSet)?);
+        Self::from_base64(&value)
+    }
+    /// The key, base64 encoded, for saving with [`open`][FieldKey::open].
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(STANDARD.encode(*self.0))
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(self.0.as_ref().into())
+    }
+    /// Encrypt `password`, returning a token.
+    pub fn encrypt(&self, password: &str) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce, password.as_bytes())
+            .expect("passwords are within the size limit");
+        let mut token = nonce.to_vec();
+        token.extend_from_slice(&ciphertext);
+        format!("{}{}", TOKEN_PREFIX, STANDARD.encode(token))
+    }
+    /// Decrypt a token produced by [`encrypt`][FieldKey::encrypt].
+    pub fn decrypt(&self, token: &str) -> Result<String, FieldError> {
+        let encoded = token
+            .strip_prefix(TOKEN_PREFIX)
+            .ok_or(FieldError::InvalidToken)?;
+        let bytes = STANDARD
+            .decode(encoded)
+            .map_err(|_| FieldError::InvalidToken)?;
+        if bytes.len() < NONCE_LENGTH {
+            return Err(FieldError::InvalidToken);
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        let plaintext = self
+            .cipher()
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| FieldError::DecryptionFailed)?;
+        String::from_utf8(plaintext).map_err(|_| FieldError::InvalidToken)
+    }
+}
+impl Debug for FieldKey {
+    // Hand-rolled to censor the key
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FieldKey(********)")
+    }
+}
+
+/// An error encountered while loading a [`FieldKey`]. It is safe to log or
+/// display this error; it will not contain keys.
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("The key is not 32 base64 encoded bytes.")]
+    InvalidKey,
+    #[error("The {KEY_VARIABLE} environment variable is not set.")]
+    NotSet,
+}
+
+/// An error encountered while decrypting a password. It is safe to log or
+/// display this error; it will not contain passwords.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldError {
+    /// A pattern matched, but it's password is encrypted and no key was set.
+    #[error("The password is encrypted, but no key was provided.")]
+    NoKey,
+    #[error("The encrypted password is malformed.")]
+    InvalidToken,
+    /// The password was encrypted with a different key, or was tampered with.
+    #[error("Failed to decrypt the password.")]
+    DecryptionFailed,
+}
+
+/// The key of a [`PgPass`]. It is configuration rather than content, so it is
+/// ignored when comparing or hashing a [`PgPass`].
+#[derive(Debug, Clone, Default)]
+pub(super) struct KeySlot(Option<FieldKey>);
+impl KeySlot {
+    /// Decrypt the password of `creds`, if it is encrypted.
+    pub(super) fn reveal(&self, creds: Credentials) -> Result<Credentials, FieldError> {
+        if !is_encrypted(&creds.password) {
+            return Ok(creds);
+        }
+        let key = self.0.as_ref().ok_or(FieldError::NoKey)?;
+        Ok(Credentials {
+            password: key.decrypt(&creds.password)?,
+            ..creds
+        })
+    }
+}
+impl PartialEq for KeySlot {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+impl Eq for KeySlot {}
+impl PartialOrd for KeySlot {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for KeySlot {
+    fn cmp(&self, _other: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
+impl Hash for KeySlot {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+impl PgPass {
+    /// Set the key used to decrypt passwords. See the
+    /// [module documentation](self).
+    pub fn set_field_key(&mut self, key: Option<FieldKey>) {
+        self.settings.field_key = KeySlot(key);
+    }
+    pub fn with_field_key(mut self, key: FieldKey) -> Self {
+        self.set_field_key(Some(key));
+        self
+    }
+    pub fn field_key(&self) -> Option<&FieldKey> {
+        self.settings.field_key.0.as_ref()
+    }
+    /// Encrypt every plaintext password with `key`. Passwords which are already
+    /// encrypted are left as they are.
+    pub fn encrypt_passwords(&mut self, key: &FieldKey) {
+        for pattern in &mut self.patterns {
+            if !is_encrypted(&pattern.password) {
+                pattern.password = key.encrypt(&pattern.password);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pgpass::{CredentialPattern, CredentialQuery, FindError};
+
+    fn query(hostname: &str) -> CredentialQuery {
+        CredentialQuery::default()
+            .hostname(hostname)
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username("username")
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let key = FieldKey::generate();
+        let token = key.encrypt("pass:word");
+        assert!(is_encrypted(&token));
+        assert_ne!(key.encrypt("pass:word"), token, "nonces are random");
+        assert_eq!(key.decrypt(&token)?, "pass:word");
+
+        assert_eq!(
+            FieldKey::generate().decrypt(&token),
+            Err(FieldError::DecryptionFailed)
+        );
+        assert_eq!(key.decrypt("enc:v1:AAAA"), Err(FieldError::InvalidToken));
+        assert_eq!(key.decrypt("enc:v1:!"), Err(FieldError::InvalidToken));
+
+        let restored = FieldKey::from_base64(&key.to_base64())?;
+        assert_eq!(restored.decrypt(&token)?, "pass:word");
+        assert!(matches!(
+            FieldKey::from_base64("c2hvcnQ="),
+            Err(KeyError::InvalidKey)
+        ));
+        assert!(!format!("{:?}", key).contains(key.to_base64().as_str()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn token_survives_encoding() -> anyhow::Result<()> {
+        let key = FieldKey::generate();
+        let token = key.encrypt("secret");
+        let pattern = CredentialPattern::default()
+            .hostname("example.com")?
+            .password(&token)?;
+
+        let mut encoded = String::new();
+        pattern.encode_into(&mut encoded);
+        assert!(encoded.starts_with("example.com:*:*:*:enc\\:v1\\:"));
+
+        let pgpass: PgPass = encoded.parse()?;
+        let pgpass = pgpass.with_field_key(key);
+        assert_eq!(
+            pgpass.find(&query("example.com"))?.unwrap().password,
+            "secret"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_the_match_is_decrypted() -> anyhow::Result<()> {
+        let key = FieldKey::generate();
+        let other = FieldKey::generate();
+        let s = format!(
+            "first:*:*:*:{}\nsecond:*:*:*:plaintext\n",
+            key.encrypt("first").replace(':', "\\:"),
+        );
+        let mut pgpass: PgPass = s.parse()?;
+        pgpass.add(
+            CredentialPattern::default()
+                .hostname("third")?
+                .password(other.encrypt("third"))?,
+        );
+
+        // Without a key, tokens are never returned as passwords
+        assert_eq!(
+            pgpass.find(&query("first")),
+            Err(FindError::Field(FieldError::NoKey))
+        );
+        assert_eq!(
+            pgpass.find(&query("second"))?.unwrap().password,
+            "plaintext"
+        );
+
+        pgpass.set_field_key(Some(key));
+        assert_eq!(pgpass.find(&query("first"))?.unwrap().password, "first");
+        // The pattern under another key doesn't affect lookups which don't use it
+        assert_eq!(
+            pgpass.find(&query("second"))?.unwrap().password,
+            "plaintext"
+        );
+        assert_eq!(
+            pgpass.find(&query("third")),
+            Err(FindError::Field(FieldError::DecryptionFailed))
+        );
+
+        // The key doesn't affect equality
+        let pgpass: PgPass = s.parse()?;
+        assert_eq!(pgpass.clone().with_field_key(other), pgpass);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lookalike_plaintext_is_a_token() -> anyhow::Result<()> {
+        let pgpass: PgPass = "example.com:*:*:*:enc\\:v1\\:plain".parse()?;
+        assert_eq!(
+            pgpass.find(&query("example.com")),
+            Err(FindError::Field(FieldError::NoKey))
+        );
+
+        let pgpass = pgpass.with_field_key(FieldKey::generate());
+        assert_eq!(
+            pgpass.find(&query("example.com")),
+            Err(FindError::Field(FieldError::InvalidToken))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypt_passwords() -> anyhow::Result<()> {
+        let key = FieldKey::generate();
+        let mut pgpass: PgPass = "one:*:*:*:1\ntwo:*:*:*:2".parse()?;
+        pgpass.encrypt_passwords(&key);
+        let encrypted = pgpass.clone();
+        pgpass.encrypt_passwords(&key);
+
+        let mut saved = Vec::new();
+        pgpass.save_into(&mut saved)?;
+        let saved = String::from_utf8(saved)?;
+        assert!(!saved.contains(":1\n"));
+        assert_eq!(saved.matches("enc\\:v1\\:").count(), 2);
+
+        // Already encrypted passwords are left alone
+        let reloaded: PgPass = saved.parse()?;
+        assert_eq!(reloaded, encrypted);
+        let reloaded = reloaded.with_field_key(key);
+        assert_eq!(reloaded.find(&query("two"))?.unwrap().password, "2");
+
+        Ok(())
+    }
+}
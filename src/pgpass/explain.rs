@@ -0,0 +1,276 @@
+//! Explaining why a query did or did not match each pattern. See [`PgPass::explain`].
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+use super::{pattern::Field, CredentialQuery, Matches, PgPass};
+
+/// A report of how a query was evaluated against every pattern in a [`PgPass`].
+/// It never contains passwords, so it is safe to display, log, or include in a
+/// support bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Explanation {
+    pub query: CredentialQuery,
+    /// The index of the pattern [`find`][PgPass::find] would use, if any.
+    pub selected: Option<usize>,
+    /// One entry for each pattern, in file order.
+    pub entries: Vec<EntryExplanation>,
+}
+
+/// How a query was evaluated against a single pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EntryExplanation {
+    /// The position of the pattern in the [`PgPass`], starting from 0.
+    pub index: usize,
+    /// The line of the file the pattern was read from, if known.
+    pub line: Option<usize>,
+    pub outcome: Outcome,
+}
+
+/// Whether a pattern matched a query.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    /// The pattern did not match, because this field differed from the query.
+    /// Fields are checked in file order, so only the first differing field is
+    /// reported.
+    Rejected { field: Field },
+    /// The pattern matched.
+    Matched {
+        /// Wildcard fields of the pattern which were populated from the query.
+        filled: Vec<Field>,
+        /// If the pattern could not be converted into credentials (for instance,
+        /// because a field was missing or a policy refused it), the reason why.
+        error: Option<String>,
+    },
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn or_wildcard<T: Display>(field: Option<&T>) -> String {
+            field.map(|x| x.to_string()).unwrap_or("*".to_string())
+        }
+
+        writeln!(
+            f,
+            "query: hostname={} port={} database={} username={}",
+            or_wildcard(self.query.hostname.as_ref()),
+            or_wildcard(self.query.port.as_ref()),
+            or_wildcard(self.query.database.as_ref()),
+            or_wildcard(self.query.username.as_ref()),
+        )?;
+        if self.entries.is_empty() {
+            writeln!(f, "no entries")?;
+        }
+        for entry in self.entries.iter() {
+            write!(f, "entry {}", entry.index)?;
+            if let Some(line) = entry.line {
+                write!(f, " (line {})", line)?;
+            }
+            match &entry.outcome {
+                Outcome::Rejected { field } => writeln!(f, ": rejected by {}", field)?,
+                Outcome::Matched { filled, error } => {
+                    f.write_str(": matched")?;
+                    if self.selected == Some(entry.index) {
+                        f.write_str(" (selected)")?;
+                    }
+                    if !filled.is_empty() {
+                        let filled: Vec<_> = filled.iter().map(|field| field.to_string()).collect();
+                        write!(f, "; filled from query: {}", filled.join(", "))?;
+                    }
+                    if let Some(error) = error {
+                        write!(f, "; error: {}", error)?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PgPass {
+    /// Explain how the query is evaluated against every pattern: which field
+    /// rejected it, or which wildcards were populated from the query if it
+    /// matched. This is intended for diagnosing lookups which unexpectedly
+    /// fail. The [`Explanation`] may be displayed as text or serialized.
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let pgpass: PgPass = "db.example.com:*:orders:alice:secret\n*:*:*:bob:secret".parse()?;
+    /// let query = CredentialQuery::default()
+    ///     .hostname("db.example.com")?
+    ///     .database("orders")?
+    ///     .username("bob")?;
+    /// let explanation = pgpass.explain(&query);
+    /// assert_eq!(
+    ///     explanation.to_string(),
+    ///     "query: hostname=db.example.com port=* database=orders username=bob\n\
+    ///      entry 0 (line 1): rejected by username\n\
+    ///      entry 1 (line 2): matched (selected); filled from query: hostname, database\n"
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn explain(&self, query: &CredentialQuery) -> Explanation {
+        let mut matches = Matches::new(self, query, self.settings.match_policy).peekable();
+        let entries = self
+            .patterns
+            .iter()
+            .enumerate()
+            .map(|(index, pattern)| {
+                let outcome = match matches.next_if(|m| m.index == index) {
+                    Some(m) => Outcome::Matched {
+                        filled: pattern
+                            .wildcards()
+                            .into_iter()
+                            .filter(|field| query.has(*field))
+                            .collect(),
+                        error: m.credentials().err().map(|e| e.to_string()),
+                    },
+                    None => Outcome::Rejected {
+                        field: pattern
+                            .mismatch(query)
+                            .expect("a pattern which did not match must have a mismatch"),
+                    },
+                };
+                EntryExplanation {
+                    index,
+                    line: self.line(index),
+                    outcome,
+                }
+            })
+            .collect();
+
+        Explanation {
+            query: query.clone(),
+            selected: self
+                .settings
+                .match_strategy
+                .select(self.find_all(query))
+                .map(|m| m.index),
+            entries,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pgpass::{CredentialPattern, MatchPolicy};
+
+    #[test]
+    fn outcomes() -> anyhow::Result<()> {
+        let s = "a:1:database:username:one\n\
+            b:*:database:username:two\n\
+            b:2:other:username:three\n\
+            b:2:database:other:four\n\
+            *:*:*:username:five\n";
+        let pgpass: PgPass = s.parse()?;
+        let query = CredentialQuery::default().hostname("b")?.port(2)?;
+
+        let explanation = pgpass.explain(&query);
+        let outcomes: Vec<_> = explanation
+            .entries
+            .iter()
+            .map(|entry| entry.outcome.clone())
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Rejected {
+                    field: Field::Hostname
+                },
+                Outcome::Matched {
+                    filled: vec![Field::Port],
+                    error: None
+                },
+                Outcome::Matched {
+                    filled: vec![],
+                    error: None
+                },
+                Outcome::Matched {
+                    filled: vec![],
+                    error: None
+                },
+                Outcome::Matched {
+                    filled: vec![Field::Hostname, Field::Port],
+                    error: Some("No database was supplied.".to_string())
+                },
+            ]
+        );
+        assert_eq!(explanation.selected, Some(1));
+
+        let query = CredentialQuery::default()
+            .hostname("b")?
+            .port(2)?
+            .database("database")?
+            .username("nobody")?;
+        let explanation = pgpass.explain(&query);
+        let rejected: Vec<_> = explanation
+            .entries
+            .iter()
+            .map(|entry| match entry.outcome {
+                Outcome::Rejected { field } => Some(field),
+                Outcome::Matched { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                Some(Field::Hostname),
+                Some(Field::Username),
+                Some(Field::Database),
+                Some(Field::Username),
+                Some(Field::Username),
+            ]
+        );
+        assert_eq!(explanation.selected, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn policy_errors() -> anyhow::Result<()> {
+        let pgpass = PgPass::default()
+            .with(CredentialPattern::default().password("secret")?)
+            .with_match_policy(MatchPolicy::StrictHost);
+        let query = CredentialQuery::default().hostname("example.com")?;
+
+        let explanation = pgpass.explain(&query);
+        assert_eq!(
+            explanation.entries[0].outcome,
+            Outcome::Matched {
+                filled: vec![Field::Hostname],
+                error: Some("Refused to use entry 0: it's hostname is a wildcard.".to_string())
+            }
+        );
+        assert_eq!(explanation.selected, Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty() {
+        let explanation = PgPass::default().explain(&Default::default());
+        assert_eq!(
+            explanation.to_string(),
+            "query: hostname=* port=* database=* username=*\nno entries\n"
+        );
+    }
+
+    #[test]
+    fn passwords_are_not_included() -> anyhow::Result<()> {
+        let pgpass: PgPass = "a:*:*:*:hunter2\n*:*:*:*:hunter3".parse()?;
+        let query = CredentialQuery::default().hostname("b")?;
+
+        let explanation = pgpass.explain(&query);
+        for s in [explanation.to_string(), format!("{:?}", explanation)] {
+            assert!(!s.contains("hunter2"));
+            assert!(!s.contains("hunter3"));
+        }
+
+        Ok(())
+    }
+}
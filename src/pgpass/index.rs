@@ -0,0 +1,243 @@
+//! A compiled index for looking up credentials in large pgpass files. See
+//! [`PgPassIndex`].
+
+use std::{collections::HashMap, hash::Hash, iter, num::NonZeroU16};
+
+use crate::Credentials;
+
+use super::{CredentialQuery, FindError, Match, PgPass};
+
+/// An index over a [`PgPass`], for files with many patterns which are queried
+/// frequently.
+///
+/// [`PgPass::find`] compares the query against every pattern in turn. The index
+/// instead keeps the patterns with a concrete value for each field in a hash map,
+/// alongside a list of the patterns with a wildcard in that field. A lookup only
+/// examines the patterns which could match the most selective field of the
+/// query.
+///
+/// The index returns exactly the same results as [`PgPass::find`], including the
+/// [`MatchStrategy`][super::MatchStrategy] and policies of the [`PgPass`]. The
+/// [`PgPass`] cannot be modified while it is indexed; use
+/// [`into_inner`][PgPassIndex::into_inner] and build a new index.
+///
+/// ```
+/// # use postgres_secrets::pgpass::*;
+/// # fn main() -> anyhow::Result<()> {
+/// let pgpass: PgPass = "example.com:*:my_database:username:password".parse()?;
+/// let index = PgPassIndex::new(pgpass);
+/// let creds = index.find(&CredentialQuery::default().hostname("example.com")?)?;
+/// assert_eq!(creds.unwrap().password, "password");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PgPassIndex {
+    pgpass: PgPass,
+    hostname: FieldIndex<String>,
+    port: FieldIndex<NonZeroU16>,
+    database: FieldIndex<String>,
+    username: FieldIndex<String>,
+}
+impl PgPassIndex {
+    pub fn new(pgpass: PgPass) -> Self {
+        let patterns = &pgpass.patterns;
+        Self {
+            hostname: FieldIndex::new(patterns.iter().map(|p| p.hostname.clone())),
+            port: FieldIndex::new(patterns.iter().map(|p| p.port)),
+            database: FieldIndex::new(patterns.iter().map(|p| p.database.clone())),
+            username: FieldIndex::new(patterns.iter().map(|p| p.username.clone())),
+            pgpass,
+        }
+    }
+    /// Equivalent to [`PgPass::find`].
+    pub fn find(&self, query: &CredentialQuery) -> Result<Option<Credentials>, FindError> {
+        let candidates = [
+            self.hostname.candidates(query.hostname.as_ref()),
+            self.port.candidates(query.port.as_ref()),
+            self.database.candidates(query.database.as_ref()),
+            self.username.candidates(query.username.as_ref()),
+        ]
+        .into_iter()
+        .flatten()
+        .min_by_key(|candidates| candidates.len());
+
+        let selected = match candidates {
+            Some(candidates) => self.pgpass.settings.match_strategy.select(
+                candidates
+                    .indices()
+                    .filter(|index| self.pgpass.patterns[*index].matches(query))
+                    .map(|index| {
+                        Match::new(
+                            &self.pgpass,
+                            index,
+                            query,
+                            self.pgpass.settings.match_policy,
+                        )
+                    }),
+            ),
+            // The query is all wildcards, so every pattern matches
+            None => self
+                .pgpass
+                .settings
+                .match_strategy
+                .select(self.pgpass.find_all(query)),
+        };
+        match selected {
+            Some(m) => Ok(Some(m.credentials()?)),
+            None => Ok(None),
+        }
+    }
+    /// The indexed [`PgPass`].
+    pub fn pgpass(&self) -> &PgPass {
+        &self.pgpass
+    }
+    /// Discard the index, returning the [`PgPass`].
+    pub fn into_inner(self) -> PgPass {
+        self.pgpass
+    }
+}
+impl From<PgPass> for PgPassIndex {
+    fn from(value: PgPass) -> Self {
+        Self::new(value)
+    }
+}
+
+/// The positions of patterns with each concrete value of a field, and of the
+/// patterns with a wildcard in that field. Positions are kept in ascending order.
+#[derive(Debug, Clone)]
+struct FieldIndex<T> {
+    concrete: HashMap<T, Vec<usize>>,
+    wildcard: Vec<usize>,
+}
+impl<T: Hash + Eq> FieldIndex<T> {
+    fn new(values: impl Iterator<Item = Option<T>>) -> Self {
+        let mut index = Self {
+            concrete: HashMap::new(),
+            wildcard: Vec::new(),
+        };
+        for (i, value) in values.enumerate() {
+            match value {
+                Some(value) => index.concrete.entry(value).or_default().push(i),
+                None => index.wildcard.push(i),
+            }
+        }
+        index
+    }
+    /// The patterns which could match this value of the field. Returns `None` if
+    /// the value is a wildcard, as then the field does not narrow the search.
+    fn candidates(&self, value: Option<&T>) -> Option<Candidates<'_>> {
+        let value = value?;
+        Some(Candidates {
+            concrete: self
+                .concrete
+                .get(value)
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+            wildcard: &self.wildcard,
+        })
+    }
+}
+
+struct Candidates<'a> {
+    concrete: &'a [usize],
+    wildcard: &'a [usize],
+}
+impl<'a> Candidates<'a> {
+    fn len(&self) -> usize {
+        self.concrete.len() + self.wildcard.len()
+    }
+    /// Merge the concrete & wildcard positions, so that they are in file order.
+    fn indices(self) -> impl Iterator<Item = usize> + 'a {
+        let mut concrete = self.concrete.iter().copied().peekable();
+        let mut wildcard = self.wildcard.iter().copied().peekable();
+        iter::from_fn(move || match (concrete.peek(), wildcard.peek()) {
+            (Some(c), Some(w)) if c < w => concrete.next(),
+            (Some(_), Some(_)) => wildcard.next(),
+            (Some(_), None) => concrete.next(),
+            (None, _) => wildcard.next(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pgpass::{CredentialPolicy, IncompleteCredential, MatchPolicy, MatchStrategy};
+
+    #[test]
+    fn simple() -> anyhow::Result<()> {
+        let s = "a:1:database:username:one\n\
+            b:*:database:username:two\n\
+            *:*:*:username:three\n\
+            b:2:database:*:four\n";
+        let pgpass: PgPass = s.parse()?;
+        let index = PgPassIndex::new(pgpass.clone());
+
+        for query in [
+            CredentialQuery::default(),
+            CredentialQuery::default().hostname("a")?,
+            CredentialQuery::default().hostname("b")?,
+            CredentialQuery::default().hostname("c")?,
+            CredentialQuery::default().hostname("b")?.port(2)?,
+            CredentialQuery::default()
+                .hostname("b")?
+                .username("other")?,
+            CredentialQuery::default().port(3)?,
+            CredentialQuery::default().database("other")?,
+            CredentialQuery::default().username("nobody")?,
+        ] {
+            assert_eq!(index.find(&query), pgpass.find(&query), "{:?}", query);
+        }
+
+        let query = CredentialQuery::default()
+            .hostname("b")?
+            .username("other")?;
+        assert_eq!(index.find(&query)?.unwrap().password, "four");
+        let query = CredentialQuery::default().hostname("c")?;
+        assert_eq!(
+            index.find(&query),
+            Err(IncompleteCredential::MissingDatabase.into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn strategy_and_policies() -> anyhow::Result<()> {
+        let s = "*:*:*:*:catch_all\nlocalhost:*:*:*:local\n";
+        let pgpass: PgPass = s.parse()?;
+        let query = CredentialQuery::default()
+            .hostname("localhost")?
+            .database("database")?
+            .username("username")?;
+
+        let index = PgPassIndex::new(pgpass.clone());
+        assert_eq!(index.find(&query)?.unwrap().password, "catch_all");
+
+        let index = PgPassIndex::new(
+            pgpass
+                .clone()
+                .with_match_strategy(MatchStrategy::MostSpecific),
+        );
+        assert_eq!(index.find(&query)?.unwrap().password, "local");
+
+        let index = PgPassIndex::new(pgpass.clone().with_match_policy(MatchPolicy::StrictHost));
+        assert_eq!(
+            index.find(&query),
+            Err(FindError::WildcardHostRefused { index: 0 })
+        );
+
+        let index = PgPassIndex::new(
+            pgpass.with_credential_policy(CredentialPolicy::default().allow_host("db")),
+        );
+        assert_eq!(
+            index.find(&query),
+            Err(FindError::HostNotAllowed {
+                hostname: "localhost".to_string()
+            })
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+//! Security lints for pgpass files.
+//!
+//! A pattern whose hostname is a wildcard will hand it's password to any host a
+//! tool is pointed at, including a mistyped or attacker-controlled hostname.
+//! [`PgPass::lint`] reports these patterns so they can be reviewed (or rejected
+//! in CI). Findings never contain passwords, so they are safe to log.
+
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, fmt::Display};
+
+use super::{pattern::Field, PgPass};
+
+/// How much damage a pattern could do if a tool was pointed at the wrong host.
+/// Risks are ordered, so they may be compared against a threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Risk {
+    /// Only the hostname is a wildcard.
+    Low,
+    /// The hostname and one other field are wildcards.
+    Medium,
+    /// The hostname and two other fields are wildcards.
+    High,
+    /// Every field is a wildcard; the password is sent to any host, for any
+    /// database and user.
+    Critical,
+}
+impl Risk {
+    fn from_wildcards(wildcards: &[Field]) -> Self {
+        match wildcards.len() {
+            0 | 1 => Risk::Low,
+            2 => Risk::Medium,
+            3 => Risk::High,
+            _ => Risk::Critical,
+        }
+    }
+}
+impl Display for Risk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Risk::Low => "low",
+            Risk::Medium => "medium",
+            Risk::High => "high",
+            Risk::Critical => "critical",
+        })
+    }
+}
+
+/// A pattern with a wildcard hostname.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WildcardHost {
+    /// The position of the pattern in the [`PgPass`], starting from 0.
+    pub index: usize,
+    /// The line of the file the pattern was read from, if known.
+    pub line: Option<usize>,
+    /// Every field of the pattern which is a wildcard (including the hostname).
+    pub wildcards: Vec<Field>,
+    pub risk: Risk,
+}
+impl Display for WildcardHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] entry {}", self.risk, self.index)?;
+        if let Some(line) = self.line {
+            write!(f, " (line {})", line)?;
+        }
+        f.write_str(": wildcard ")?;
+        for (i, field) in self.wildcards.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", field)?;
+        }
+        Ok(())
+    }
+}
+
+/// The results of [`PgPass::lint`]. Findings are ranked from most to least
+/// dangerous; patterns with the same number of wildcards are kept in file order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LintReport {
+    pub findings: Vec<WildcardHost>,
+}
+impl LintReport {
+    /// The highest risk of any finding, or `None` if there were no findings.
+    pub fn max_risk(&self) -> Option<Risk> {
+        self.findings.iter().map(|finding| finding.risk).max()
+    }
+    /// Returns true if any finding is at least as risky as `threshold`. This is
+    /// intended for failing CI jobs.
+    pub fn exceeds(&self, threshold: Risk) -> bool {
+        self.max_risk().is_some_and(|risk| risk >= threshold)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+impl Display for LintReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for finding in self.findings.iter() {
+            writeln!(f, "{}", finding)?;
+        }
+        Ok(())
+    }
+}
+
+impl PgPass {
+    /// Report every pattern whose hostname is a wildcard, ranked by how many
+    /// fields are wildcards. See the [`lint`][super::lint] module.
+    ///
+    /// ```
+    /// # use postgres_secrets::PgPass;
+    /// # use postgres_secrets::pgpass::lint::Risk;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let pgpass: PgPass = "example.com:*:*:*:secret\n*:*:*:*:secret".parse()?;
+    /// let report = pgpass.lint();
+    /// assert_eq!(report.findings.len(), 1);
+    /// assert_eq!(report.max_risk(), Some(Risk::Critical));
+    /// assert!(report.exceeds(Risk::High));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn lint(&self) -> LintReport {
+        let mut findings: Vec<_> = self
+            .patterns
+            .iter()
+            .enumerate()
+            .filter(|(_, pattern)| pattern.hostname.is_none())
+            .map(|(index, pattern)| {
+                let wildcards = pattern.wildcards();
+                WildcardHost {
+                    index,
+                    line: self.line(index),
+                    risk: Risk::from_wildcards(&wildcards),
+                    wildcards,
+                }
+            })
+            .collect();
+        // Stable, so ties remain in file order
+        findings.sort_by_key(|finding| Reverse(finding.wildcards.len()));
+
+        LintReport { findings }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pgpass::CredentialPattern;
+
+    #[test]
+    fn concrete_hostnames_are_not_reported() -> anyhow::Result<()> {
+        let pgpass = PgPass::default()
+            .with(
+                CredentialPattern::default()
+                    .hostname("localhost")?
+                    .password("a")?,
+            )
+            .with(
+                CredentialPattern::default()
+                    .hostname("example.com")?
+                    .port(123)?
+                    .database("database")?
+                    .username("username")?
+                    .password("b")?,
+            );
+
+        let report = pgpass.lint();
+        assert!(report.is_empty());
+        assert_eq!(report.max_risk(), None);
+        assert!(!report.exceeds(Risk::Low));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ranked_by_wildcards() -> anyhow::Result<()> {
+        let pgpass = PgPass::default()
+            .with(
+                CredentialPattern::default()
+                    .port(123)?
+                    .database("database")?
+                    .username("username")?
+                    .password("a")?,
+            )
+            .with(
+                CredentialPattern::default()
+                    .username("username")?
+                    .password("b")?,
+            )
+            .with(CredentialPattern::default().password("c")?)
+            .with(CredentialPattern::default().port(123)?.password("d")?)
+            .with(
+                CredentialPattern::default()
+                    .database("database")?
+                    .password("e")?,
+            );
+
+        let report = pgpass.lint();
+        let ranked: Vec<_> = report
+            .findings
+            .iter()
+            .map(|finding| (finding.index, finding.risk))
+            .collect();
+        assert_eq!(
+            ranked,
+            vec![
+                (2, Risk::Critical),
+                (1, Risk::High),
+                (3, Risk::High),
+                (4, Risk::High),
+                (0, Risk::Low),
+            ]
+        );
+        assert_eq!(
+            report.findings[1].wildcards,
+            vec![Field::Hostname, Field::Port, Field::Database]
+        );
+        assert!(report.exceeds(Risk::Critical));
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_does_not_contain_passwords() -> anyhow::Result<()> {
+        let pgpass: PgPass = "*:*:*:*:hunter2".parse()?;
+        let report = pgpass.lint();
+        assert_eq!(
+            report.to_string(),
+            "[critical] entry 0 (line 1): wildcard hostname, port, database, username\n"
+        );
+
+        assert!(!report.to_string().contains("hunter2"));
+        assert!(!format!("{:?}", report).contains("hunter2"));
+
+        Ok(())
+    }
+}
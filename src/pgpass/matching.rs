@@ -0,0 +1,306 @@
+//! Iterating over every pattern which matches a query, and choosing between them.
+//! See [`PgPass::find_all`] and [`MatchStrategy`].
+
+use serde::{Deserialize, Serialize};
+use std::{fmt::Debug, iter::Enumerate, slice};
+
+use crate::Credentials;
+
+use super::{
+    pattern::HasPasswordTrue, CredentialPattern, CredentialQuery, FindError, MatchPolicy, PgPass,
+};
+
+/// Controls which of the matching patterns [`PgPass::find`] uses. See
+/// [`PgPass::set_match_strategy`].
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum MatchStrategy {
+    /// Use the first matching pattern. This is the behavior of `libpq`.
+    #[default]
+    FirstMatch,
+    /// Use the matching pattern with the most fields which are not wildcards
+    /// (see [`CredentialPattern::specificity`]). Ties are broken by file order.
+    ///
+    /// Use [`PgPass::sort_by_specificity`] to reorder a file so that
+    /// [`FirstMatch`][MatchStrategy::FirstMatch] gives the same results.
+    MostSpecific,
+}
+
+impl MatchStrategy {
+    /// Choose one of the matches (which must be in file order).
+    pub(crate) fn select<'a>(
+        self,
+        mut matches: impl Iterator<Item = Match<'a>>,
+    ) -> Option<Match<'a>> {
+        match self {
+            MatchStrategy::FirstMatch => matches.next(),
+            MatchStrategy::MostSpecific => matches.reduce(|best, m| {
+                if m.pattern.specificity() > best.pattern.specificity() {
+                    m
+                } else {
+                    best
+                }
+            }),
+        }
+    }
+}
+
+/// A pattern which matched a query. See [`PgPass::find_all`].
+#[derive(Clone)]
+pub struct Match<'a> {
+    /// The position of the pattern in the [`PgPass`], starting from 0.
+    pub index: usize,
+    /// The line of the file the pattern was read from, starting from 1. This is
+    /// `None` if the pattern was not read from a file.
+    pub line: Option<usize>,
+    pub pattern: &'a CredentialPattern<HasPasswordTrue>,
+    query: &'a CredentialQuery,
+    pgpass: &'a PgPass,
+    match_policy: MatchPolicy,
+}
+impl<'a> Match<'a> {
+    pub(crate) fn new(
+        pgpass: &'a PgPass,
+        index: usize,
+        query: &'a CredentialQuery,
+        match_policy: MatchPolicy,
+    ) -> Self {
+        Self {
+            index,
+            line: pgpass.line(index),
+            pattern: &pgpass.patterns[index],
+            query,
+            pgpass,
+            match_policy,
+        }
+    }
+    /// Convert the match into [`Credentials`], exactly as [`PgPass::find`] would.
+    /// Wildcards in the pattern are populated from the query, and the policies
+    /// of the [`PgPass`] are checked.
+    pub fn credentials(&self) -> Result<Credentials, FindError> {
+        self.match_policy
+            .check(self.index, self.pattern.hostname.is_none())?;
+        let creds = PgPass::pattern_to_creds(self.query, self.pattern)?;
+        if let Some(policy) = self.pgpass.settings.credential_policy.as_ref() {
+            policy.check(&creds)?;
+        }
+        #[cfg(feature = "field-encryption")]
+        let creds = self.pgpass.settings.field_key.reveal(creds)?;
+        Ok(creds)
+    }
+}
+impl Debug for Match<'_> {
+    // Hand-rolled to censor passwords
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Match")
+            .field("index", &self.index)
+            .field("line", &self.line)
+            .field("hostname", &self.pattern.hostname)
+            .field("port", &self.pattern.port)
+            .field("database", &self.pattern.database)
+            .field("username", &self.pattern.username)
+            .field("password", &"[ Censored ]")
+            .finish()
+    }
+}
+
+/// An iterator over every pattern matching a query. See [`PgPass::find_all`].
+#[derive(Clone)]
+pub struct Matches<'a> {
+    patterns: Enumerate<slice::Iter<'a, CredentialPattern<HasPasswordTrue>>>,
+    query: &'a CredentialQuery,
+    pgpass: &'a PgPass,
+    match_policy: MatchPolicy,
+}
+impl<'a> Matches<'a> {
+    pub(crate) fn new(
+        pgpass: &'a PgPass,
+        query: &'a CredentialQuery,
+        match_policy: MatchPolicy,
+    ) -> Self {
+        Self {
+            patterns: pgpass.patterns.iter().enumerate(),
+            query,
+            pgpass,
+            match_policy,
+        }
+    }
+}
+impl<'a> Iterator for Matches<'a> {
+    type Item = Match<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let query = self.query;
+        let (index, _) = self.patterns.find(|(_, pattern)| pattern.matches(query))?;
+
+        Some(Match::new(self.pgpass, index, query, self.match_policy))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pgpass::{CredentialPolicy, IncompleteCredential};
+
+    #[test]
+    fn every_match_in_order() -> anyhow::Result<()> {
+        let s = "# Comment\n\
+            a:1:database:username:one\n\
+            b:2:other:username:two\n\
+            *:*:database:*:three\n";
+        let pgpass: PgPass = s.parse()?;
+        let query = CredentialQuery::default().database("database")?;
+
+        let matches: Vec<_> = pgpass
+            .find_all(&query)
+            .map(|m| (m.index, m.line, m.pattern.password.as_str()))
+            .collect();
+        assert_eq!(matches, vec![(0, Some(2), "one"), (2, Some(4), "three")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lines_are_unknown_for_added_patterns() -> anyhow::Result<()> {
+        let pgpass = PgPass::default().with(
+            CredentialPattern::default()
+                .hostname("localhost")?
+                .password("password")?,
+        );
+        let query = CredentialQuery::default();
+
+        let m = pgpass.find_all(&query).next().unwrap();
+        assert_eq!(m.index, 0);
+        assert_eq!(m.line, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn credentials_are_substituted() -> anyhow::Result<()> {
+        let pgpass: PgPass = "*:*:database:*:one\nlocalhost:*:*:username:two".parse()?;
+        let query = CredentialQuery::default().hostname("localhost")?;
+
+        let mut matches = pgpass.find_all(&query);
+        assert_eq!(
+            matches.next().unwrap().credentials(),
+            Err(IncompleteCredential::MissingUsername.into())
+        );
+        assert_eq!(
+            matches.next().unwrap().credentials(),
+            Err(IncompleteCredential::MissingDatabase.into())
+        );
+        assert!(matches.next().is_none());
+
+        let query = query.database("database")?.username("username")?;
+        let creds: Vec<_> = pgpass
+            .find_all(&query)
+            .map(|m| m.credentials())
+            .collect::<Result<_, _>>()?;
+        assert_eq!(creds.len(), 2);
+        assert_eq!(creds[0].password, "one");
+        assert_eq!(creds[1].password, "two");
+        assert_eq!(Some(creds[0].clone()), pgpass.find(&query)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn credentials_check_policies() -> anyhow::Result<()> {
+        let pgpass: PgPass = "*:*:*:*:one\nlocalhost:*:*:*:two".parse()?;
+        let pgpass = pgpass.with_credential_policy(CredentialPolicy::default().allow_host("db"));
+        let query = CredentialQuery::default()
+            .hostname("localhost")?
+            .database("database")?
+            .username("username")?;
+
+        let mut matches = pgpass.find_all(&query);
+        let expected = Err(FindError::HostNotAllowed {
+            hostname: "localhost".to_string(),
+        });
+        assert_eq!(matches.next().unwrap().credentials(), expected);
+        assert_eq!(matches.next().unwrap().credentials(), expected);
+
+        let pgpass = pgpass.with_match_policy(MatchPolicy::StrictHost);
+        let mut matches = pgpass.find_all(&query);
+        assert_eq!(
+            matches.next().unwrap().credentials(),
+            Err(FindError::WildcardHostRefused { index: 0 })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn most_specific() -> anyhow::Result<()> {
+        let s = "*:*:*:*:catch_all\n\
+            *:*:database:*:database\n\
+            localhost:*:database:*:localhost\n\
+            *:123:database:*:port\n\
+            localhost:*:database:username:exact\n";
+        let pgpass: PgPass = s.parse()?;
+        let query = CredentialQuery::default()
+            .hostname("localhost")?
+            .database("database")?
+            .username("username")?;
+
+        assert_eq!(pgpass.find(&query)?.unwrap().password, "catch_all");
+
+        let pgpass = pgpass.with_match_strategy(MatchStrategy::MostSpecific);
+        assert_eq!(pgpass.find(&query)?.unwrap().password, "exact");
+
+        // Ties go to the first pattern
+        let query = CredentialQuery::default()
+            .hostname("localhost")?
+            .port(123)?
+            .database("database")?
+            .username("other")?;
+        assert_eq!(pgpass.find(&query)?.unwrap().password, "localhost");
+        assert_eq!(pgpass.explain(&query).selected, Some(2));
+
+        let query = CredentialQuery::default().username("nobody")?;
+        let pgpass: PgPass = "a:*:*:somebody:password".parse()?;
+        let pgpass = pgpass.with_match_strategy(MatchStrategy::MostSpecific);
+        assert_eq!(pgpass.find(&query)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sort_by_specificity() -> anyhow::Result<()> {
+        let s = "*:*:*:*:catch_all\n\
+            localhost:*:database:*:localhost\n\
+            *:*:database:*:database\n\
+            *:123:database:*:port\n\
+            localhost:*:database:username:exact\n";
+        let mut pgpass: PgPass = s.parse()?;
+        pgpass.sort_by_specificity();
+
+        let passwords: Vec<_> = pgpass
+            .patterns
+            .iter()
+            .map(|pattern| pattern.password.as_str())
+            .collect();
+        assert_eq!(
+            passwords,
+            vec!["exact", "localhost", "port", "database", "catch_all"]
+        );
+        // Lines follow their patterns
+        let lines: Vec<_> = (0..5).map(|index| pgpass.line(index)).collect();
+        assert_eq!(lines, vec![Some(5), Some(2), Some(4), Some(3), Some(1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn debug_is_censored() -> anyhow::Result<()> {
+        let pgpass: PgPass = "*:*:*:*:hunter2".parse()?;
+        let query = CredentialQuery::default();
+
+        let m = pgpass.find_all(&query).next().unwrap();
+        assert!(!format!("{:?}", m).contains("hunter2"));
+
+        Ok(())
+    }
+}
@@ -8,14 +8,35 @@
 // other formats (such as the connection service file) without reorganizing the project,
 // which would result in a breaking change.
 
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(any(feature = "postgres", feature = "tokio-postgres"))]
+pub mod config;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
+#[cfg(feature = "field-encryption")]
+pub mod encrypted_field;
+pub mod explain;
+pub mod index;
+pub mod lint;
+pub mod matching;
+pub mod options;
 mod parser;
 pub mod pattern;
+pub mod policy;
+pub mod raw;
+#[cfg(feature = "sqlx")]
+pub mod sqlx;
+pub mod stream;
+pub mod watch;
 
 use log::{debug, error, trace, warn};
 use serde::{Deserialize, Serialize};
 use std::{
+    cmp::Ordering,
     env,
     fs::File,
+    hash::{Hash, Hasher},
     io::{self, Read, Write},
     marker::PhantomData,
     path::{Path, PathBuf},
@@ -25,11 +46,15 @@ use thiserror::Error;
 
 use crate::Credentials;
 
+pub use self::index::PgPassIndex;
+pub use self::matching::{Match, MatchStrategy, Matches};
+pub use self::options::LoadOptions;
 pub use self::parser::field::FieldError;
 pub use self::parser::port::PortError;
 pub use self::parser::ParsingError;
-pub use self::pattern::{CredentialPattern, CredentialQuery};
+pub use self::pattern::{BorrowedCredentialPattern, CredentialPattern, CredentialQuery};
 use self::pattern::{HasPasswordTrue, InvalidField};
+pub use self::policy::{CredentialPolicy, MatchPolicy};
 
 // Constants copied from Postgres documentation
 pub const FILENAME: &str = ".pgpass";
@@ -44,6 +69,9 @@ pub const ESCAPE_CHAR: char = '\\';
 pub const WILDCARD: &str = "*";
 pub const WILDCARD_CHAR: char = '*';
 pub const ESCAPABLE: [char; 3] = [ESCAPE_CHAR, WILDCARD_CHAR, DELIMITER_CHAR];
+/// The UTF-8 byte order mark, which is ignored at the start of a file.
+pub const BOM: &[u8] = b"\xEF\xBB\xBF";
+pub const BOM_CHAR: char = '\u{FEFF}';
 
 /// A set of Postgres credentials that can be queried with a simple pattern-matching
 /// scheme.
@@ -106,6 +134,15 @@ pub const ESCAPABLE: [char; 3] = [ESCAPE_CHAR, WILDCARD_CHAR, DELIMITER_CHAR];
 ///
 /// All others are considered invalid.
 ///
+/// ## Byte order marks
+///
+/// A UTF-8 byte order mark at the start of the file is ignored, once. If the
+/// hostname of the first pattern itself starts with U+FEFF, [`save_into`][a]
+/// writes a byte order mark before it, so that the hostname is read back
+/// unchanged. A U+FEFF anywhere else is an ordinary character.
+///
+/// [a]: PgPass::save_into
+///
 /// ## Comments
 ///
 /// Any lines starting with `#` will be considered a comment and ignored.
@@ -121,6 +158,13 @@ pub const ESCAPABLE: [char; 3] = [ESCAPE_CHAR, WILDCARD_CHAR, DELIMITER_CHAR];
 /// [`IncompleteCredential`] error will be returned. The exception is the port
 /// field, which will be substituted for the [default port][super::DEFAULT_PORT].
 ///
+/// By default, the first matching pattern is used, as in `libpq`. A
+/// [`MatchStrategy`] may be used to prefer the most specific pattern instead.
+///
+/// A [`MatchPolicy`] may be used to refuse patterns with a wildcard hostname, and
+/// a [`CredentialPolicy`] may be used to restrict which hosts credentials are
+/// released to.
+///
 /// # Caveats
 /// - This does not behave precisely the same as the parser in `libpq`.
 ///     While unlikely, this could lead to bugs or confusing behavior
@@ -134,33 +178,112 @@ pub const ESCAPABLE: [char; 3] = [ESCAPE_CHAR, WILDCARD_CHAR, DELIMITER_CHAR];
 /// - `libpq` performs a permissions check on the pgpass file, and will
 ///     not open a file which is too permissive. This library does not
 ///     perform this check.
+/// - `libpq` treats fields as bytes, while this requires the file to be
+///     UTF-8. Use [`RawPgPass`][raw::RawPgPass] for files in other encodings.
 ///
+#[allow(clippy::doc_overindented_list_items)]
 #[derive(Default, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct PgPass {
     patterns: Vec<CredentialPattern<HasPasswordTrue>>,
+    #[serde(skip)]
+    settings: Settings,
+    #[serde(skip)]
+    lines: LineNumbers,
+}
+
+/// The settings of a [`PgPass`], which configure how it is queried rather than
+/// what it contains. They are not serialized, and are carried over as a whole
+/// when a [`WatchedPgPass`][watch::WatchedPgPass] reloads it's file.
+#[derive(Default, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Settings {
+    match_strategy: MatchStrategy,
+    match_policy: MatchPolicy,
+    credential_policy: Option<CredentialPolicy>,
+    #[cfg(feature = "field-encryption")]
+    field_key: encrypted_field::KeySlot,
 }
 
 impl PgPass {
     /// Automatically locate and load the pgpass file.
     /// See [`locate`][PgPass::locate] for more.
+    ///
+    /// The default [`LoadOptions`] are used; see [`load_with`][PgPass::load_with].
     pub fn load() -> Result<Self, LoadError> {
+        Self::load_with(&LoadOptions::default())
+    }
+    /// Automatically locate and load the pgpass file, enforcing the given limits.
+    pub fn load_with(options: &LoadOptions) -> Result<Self, LoadError> {
         let Some(path) = Self::locate() else {
             return Err(LoadError::CouldNotLocate);
         };
-        Self::open(path)
+        Self::open_with(path, options)
     }
     /// Load credentials from the given file.
-    pub fn read<F: Read>(mut f: F) -> Result<Self, LoadError> {
-        let mut contents = Vec::with_capacity(8192);
-        f.read_to_end(&mut contents)?;
-        let s = str::from_utf8(&contents)?;
-
-        Ok(parser::pgpass(s)?)
+    ///
+    /// The default [`LoadOptions`] are used; see [`read_with`][PgPass::read_with].
+    pub fn read<F: Read>(f: F) -> Result<Self, LoadError> {
+        Self::read_with(f, &LoadOptions::default())
+    }
+    /// Load credentials from the given file, enforcing the given limits. Reading
+    /// stops as soon as the file exceeds the maximum size.
+    ///
+    /// A leading UTF-8 byte order mark is ignored. Files which are not valid
+    /// UTF-8 are rejected; see [`RawPgPass`][raw::RawPgPass] to read them.
+    pub fn read_with<F: Read>(f: F, options: &LoadOptions) -> Result<Self, LoadError> {
+        let contents = options.read_limited(f)?;
+        Self::parse_contents(&contents, options)
+    }
+    /// Parse a file which has already been read by [`LoadOptions::read_limited`].
+    fn parse_contents(contents: &[u8], options: &LoadOptions) -> Result<Self, LoadError> {
+        options.check_line_lengths(contents)?;
+        let s = str::from_utf8(contents).map_err(|source| LoadError::Utf8 {
+            line: line_of(contents, source.valid_up_to()),
+            source,
+        })?;
+
+        let mut pgpass = PgPass::default();
+        parser::for_each_pattern(s, |pattern, line| {
+            options.check_entries(pgpass.patterns.len() + 1)?;
+            pgpass.patterns.push(pattern.into_owned());
+            pgpass.lines.0.push(Some(line));
+            Ok::<_, LoadError>(())
+        })?;
+        Ok(pgpass)
     }
     /// Load credentials from the file at the given path.
+    ///
+    /// The default [`LoadOptions`] are used; see [`open_with`][PgPass::open_with].
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        Self::open_with(path, &LoadOptions::default())
+    }
+    /// Load credentials from the file at the given path, enforcing the given limits.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Self, LoadError> {
         let f = File::open(path.as_ref())?;
-        Self::read(f)
+        Self::read_with(f, options)
+    }
+    /// Parse the patterns of a pgpass file without copying them. Fields which
+    /// contain no escape sequences borrow from `s`, which avoids an allocation per
+    /// field when parsing large files. Comments and blank lines are skipped.
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # use std::borrow::Cow;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let s = "example.com:*:my_database:user\\:name:password";
+    /// let patterns = PgPass::parse_borrowed(s)?;
+    /// assert_eq!(patterns[0].hostname, Some(Cow::Borrowed("example.com")));
+    /// assert_eq!(patterns[0].username.as_deref(), Some("user:name"));
+    ///
+    /// let mut pgpass = PgPass::default();
+    /// for pattern in patterns {
+    ///     pgpass.add(pattern.into_owned());
+    /// }
+    /// assert_eq!(pgpass, s.parse()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn parse_borrowed(s: &str) -> Result<Vec<BorrowedCredentialPattern<'_>>, ParsingError> {
+        parser::pgpass_borrowed(s)
     }
     /// Automatically locate the pgpass file. If the `PGPASSFILE` environment variable
     /// is set, then it's value will be used. Otherwise, `~/.pgpass` will be used on
@@ -169,25 +292,30 @@ impl PgPass {
     /// This behavior is specified in the
     /// [pgpass documentation](https://www.postgresql.org/docs/current/libpq-pgpass.html).
     pub fn locate() -> Option<PathBuf> {
+        match Self::locate_candidate()? {
+            Candidate::Environment(path) => Some(path),
+            Candidate::Default(path) => {
+                let is_file = path.is_file();
+                Self::located_default(path, is_file)
+            }
+        }
+    }
+    /// The first step of [`locate`][PgPass::locate], which does not touch the
+    /// filesystem.
+    fn locate_candidate() -> Option<Candidate> {
         if let Some(path) = env::var_os(PATH_ENVIRONMENT_VAR).map(PathBuf::from) {
             trace!(
                 "Using pgpass file from environment variable: {:?}",
                 &path.as_os_str()
             );
-            return Some(path);
+            return Some(Candidate::Environment(path));
         } else {
             debug!("Did not find PGPASSFILE envrironment variable")
         }
         #[cfg(unix)]
         {
             if let Some(home) = home::home_dir() {
-                let path = home.join(FILENAME);
-                if path.is_file() {
-                    trace!("Using pgpass file from home: {:?}", &path.as_os_str());
-                    return Some(path);
-                } else {
-                    debug!("~/.pgpass did not exist or was not a file")
-                }
+                return Some(Candidate::Default(home.join(FILENAME)));
             } else {
                 warn!("Failed to find home directory")
             }
@@ -195,13 +323,7 @@ impl PgPass {
         #[cfg(windows)]
         {
             if let Some(app_data) = env::var_os("APPDATA").map(PathBuf::from) {
-                let path = app_data.join(FILENAME_WINDOWS);
-                if path.is_file() {
-                    trace!("Using pgpass file from appdata: {:?}", &path.as_os_str());
-                    return Some(path);
-                } else {
-                    debug!("%APPDATA%\\postgresql\\pgpass.conf did not exist or was not a file")
-                }
+                return Some(Candidate::Default(app_data.join(FILENAME_WINDOWS)));
             } else {
                 warn!("Failed to find app data directory")
             }
@@ -210,10 +332,37 @@ impl PgPass {
         error!("Failed to locate pgpass file");
         None
     }
+    /// The second step of [`locate`][PgPass::locate], once we know whether the
+    /// default path is a file.
+    fn located_default(path: PathBuf, is_file: bool) -> Option<PathBuf> {
+        if is_file {
+            #[cfg(unix)]
+            trace!("Using pgpass file from home: {:?}", &path.as_os_str());
+            #[cfg(windows)]
+            trace!("Using pgpass file from appdata: {:?}", &path.as_os_str());
+            return Some(path);
+        }
+        #[cfg(unix)]
+        debug!("~/.pgpass did not exist or was not a file");
+        #[cfg(windows)]
+        debug!("%APPDATA%\\postgresql\\pgpass.conf did not exist or was not a file");
+
+        error!("Failed to locate pgpass file");
+        None
+    }
     /// Write the patterns to a file.
+    ///
+    /// A byte order mark at the start of a file is ignored when it is loaded, so
+    /// if the first hostname starts with one, another is written before it.
     pub fn save_into<F: Write>(&self, f: &mut F) -> Result<(), io::Error> {
         let mut iterator = self.patterns.iter();
         let first = iterator.next();
+        if first
+            .and_then(|cred| cred.hostname.as_ref())
+            .is_some_and(|hostname| hostname.starts_with(BOM_CHAR))
+        {
+            f.write_all(BOM)?;
+        }
         let capacity = first
             .as_ref()
             .map(|cred| cred.capacity_needed())
@@ -239,7 +388,8 @@ impl PgPass {
     /// Add a pattern to the file. Patterns are evaluated in order, so this new
     /// pattern will have the lowest precedence.
     pub fn add(&mut self, cred: CredentialPattern<HasPasswordTrue>) {
-        self.patterns.push(cred)
+        self.patterns.push(cred);
+        self.lines.0.push(None);
     }
     /// Builder interface to [`add`][a].
     ///
@@ -248,9 +398,121 @@ impl PgPass {
         self.add(cred);
         self
     }
+    /// Insert a pattern at `index`, so that it takes precedence over the patterns
+    /// after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than the number of patterns.
+    pub fn insert(&mut self, index: usize, cred: CredentialPattern<HasPasswordTrue>) {
+        self.lines.0.resize(self.patterns.len(), None);
+        self.patterns.insert(index, cred);
+        self.lines.0.insert(index, None);
+    }
+    /// Keep only the patterns for which `f` returns `true`, in their current
+    /// order.
+    pub fn retain(&mut self, mut f: impl FnMut(&CredentialPattern<HasPasswordTrue>) -> bool) {
+        self.retain_mut(|pattern| f(pattern));
+    }
+    /// Like [`retain`][PgPass::retain], but `f` may modify the patterns, for
+    /// instance to wipe the password of a pattern which is removed.
+    pub(crate) fn retain_mut(
+        &mut self,
+        mut f: impl FnMut(&mut CredentialPattern<HasPasswordTrue>) -> bool,
+    ) {
+        let mut lines = std::mem::take(&mut self.lines.0);
+        lines.resize(self.patterns.len(), None);
+        (self.patterns, self.lines.0) = self
+            .patterns
+            .drain(..)
+            .zip(lines)
+            .filter_map(|(mut pattern, line)| f(&mut pattern).then_some((pattern, line)))
+            .unzip();
+    }
+    /// The number of patterns.
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
     /// Remove all patterns.
     pub fn clear(&mut self) {
-        self.patterns.clear()
+        self.patterns.clear();
+        self.lines.0.clear();
+    }
+    /// Set the [`MatchStrategy`] used by [`find`][PgPass::find] and
+    /// [`query`][PgPass::query]. The default is [`MatchStrategy::FirstMatch`].
+    pub fn set_match_strategy(&mut self, strategy: MatchStrategy) {
+        self.settings.match_strategy = strategy
+    }
+    /// Builder interface to [`set_match_strategy`][a].
+    ///
+    /// [a]: PgPass::set_match_strategy
+    pub fn with_match_strategy(mut self, strategy: MatchStrategy) -> Self {
+        self.set_match_strategy(strategy);
+        self
+    }
+    /// Reorder the patterns from most to least [specific][a], keeping patterns
+    /// with the same specificity in their current order. Afterwards,
+    /// [`MatchStrategy::FirstMatch`] and [`MatchStrategy::MostSpecific`] will
+    /// give the same results. Save the file to make the change permanent.
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let mut pgpass: PgPass = "*:*:*:*:catch_all\nlocalhost:*:*:*:local".parse()?;
+    /// pgpass.sort_by_specificity();
+    /// let creds = pgpass.query()
+    ///     .hostname("localhost")?
+    ///     .database("my_database")?
+    ///     .username("username")?
+    ///     .find()?
+    ///     .unwrap();
+    /// assert_eq!(creds.password, "local");
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [a]: CredentialPattern::specificity
+    pub fn sort_by_specificity(&mut self) {
+        let mut lines = std::mem::take(&mut self.lines.0);
+        lines.resize(self.patterns.len(), None);
+        let mut entries: Vec<_> = self.patterns.drain(..).zip(lines).collect();
+        entries.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.specificity()));
+        (self.patterns, self.lines.0) = entries.into_iter().unzip();
+    }
+    /// Set the [`MatchPolicy`] used by [`find`][PgPass::find] and
+    /// [`query`][PgPass::query]. The default is [`MatchPolicy::Permissive`].
+    ///
+    /// The policy is configuration rather than content, so it is not
+    /// serialized. A deserialized [`PgPass`] is permissive until the policy is
+    /// set again.
+    pub fn set_match_policy(&mut self, policy: MatchPolicy) {
+        self.settings.match_policy = policy
+    }
+    /// Builder interface to [`set_match_policy`][a].
+    ///
+    /// [a]: PgPass::set_match_policy
+    pub fn with_match_policy(mut self, policy: MatchPolicy) -> Self {
+        self.set_match_policy(policy);
+        self
+    }
+    /// Set the [`CredentialPolicy`] checked by [`find`][PgPass::find] and
+    /// [`query`][PgPass::query] before credentials are returned. `None` (the
+    /// default) allows credentials to be released to any host.
+    ///
+    /// Like the [`MatchPolicy`], the policy is not serialized, and must be set
+    /// again after deserializing.
+    pub fn set_credential_policy(&mut self, policy: Option<CredentialPolicy>) {
+        self.settings.credential_policy = policy
+    }
+    /// Builder interface to [`set_credential_policy`][a].
+    ///
+    /// [a]: PgPass::set_credential_policy
+    pub fn with_credential_policy(mut self, policy: CredentialPolicy) -> Self {
+        self.set_credential_policy(Some(policy));
+        self
     }
 
     fn pattern_to_creds(
@@ -279,12 +541,17 @@ impl PgPass {
         }
         .try_into()
     }
-    /// Returns the first set of credentials matching the query (if one exists).
+    /// Returns the first set of credentials matching the query (if one exists),
+    /// or the most specific if [`MatchStrategy::MostSpecific`] is used.
     /// Any wildcard fields in the credential pattern will be populated from the
     /// query. If no port is supplied, the default port (5432) will be used. If
     /// any other fields are missing, an error will be returned. See [`query`][a]
     /// for a more ergonomic interface.
     ///
+    /// If the matching pattern is refused by the [`MatchPolicy`], or the resulting
+    /// host is not allowed by the [`CredentialPolicy`], an error is returned rather
+    /// than falling through to later patterns.
+    ///
     /// ```
     /// # use postgres_secrets::pgpass::*;
     /// # fn main() -> anyhow::Result<()> {
@@ -305,42 +572,54 @@ impl PgPass {
     /// ```
     ///
     /// [a]: PgPass::query
-    pub fn find(
+    pub fn find(&self, query: &CredentialQuery) -> Result<Option<Credentials>, FindError> {
+        self.find_with_policy(query, self.settings.match_policy)
+    }
+    fn find_with_policy(
         &self,
         query: &CredentialQuery,
-    ) -> Result<Option<Credentials>, IncompleteCredential> {
-        for pattern in self.patterns.iter() {
-            if let Some((query_hostname, pattern_hostname)) =
-                query.hostname.as_ref().zip(pattern.hostname.as_ref())
-            {
-                if query_hostname != pattern_hostname {
-                    continue;
-                }
-            }
-            if let Some((query_port, pattern_port)) = query.port.zip(pattern.port) {
-                if query_port != pattern_port {
-                    continue;
-                }
-            }
-            if let Some((query_database, pattern_database)) =
-                query.database.as_ref().zip(pattern.database.as_ref())
-            {
-                if query_database != pattern_database {
-                    continue;
-                }
-            }
-            if let Some((query_username, pattern_username)) =
-                query.username.as_ref().zip(pattern.username.as_ref())
-            {
-                if query_username != pattern_username {
-                    continue;
-                }
-            }
-
-            return Ok(Some(Self::pattern_to_creds(query, pattern)?));
+        match_policy: MatchPolicy,
+    ) -> Result<Option<Credentials>, FindError> {
+        match self
+            .settings
+            .match_strategy
+            .select(self.find_all_with_policy(query, match_policy))
+        {
+            Some(m) => Ok(Some(m.credentials()?)),
+            None => Ok(None),
         }
-
-        Ok(None)
+    }
+    /// Lazily iterate over every pattern matching the query, in file order. With
+    /// the default [`MatchStrategy`], the first [`Match`] is the one
+    /// [`find`][PgPass::find] would use. This is useful for debugging, or for
+    /// failing over to other credentials.
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let pgpass: PgPass = "primary:*:db:user:one\nreplica:*:db:user:two".parse()?;
+    /// let query = CredentialQuery::default().database("db")?;
+    /// let hosts: Vec<_> = pgpass
+    ///     .find_all(&query)
+    ///     .map(|m| m.credentials().map(|creds| creds.hostname))
+    ///     .collect::<Result<_, _>>()?;
+    /// assert_eq!(hosts, vec!["primary", "replica"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn find_all<'a>(&'a self, query: &'a CredentialQuery) -> Matches<'a> {
+        self.find_all_with_policy(query, self.settings.match_policy)
+    }
+    fn find_all_with_policy<'a>(
+        &'a self,
+        query: &'a CredentialQuery,
+        match_policy: MatchPolicy,
+    ) -> Matches<'a> {
+        Matches::new(self, query, match_policy)
+    }
+    /// The line of the file the pattern at `index` was read from, if known.
+    fn line(&self, index: usize) -> Option<usize> {
+        self.lines.0.get(index).copied().flatten()
     }
     /// A more ergonomic interface to [`find`][a], allowing you to construct queries
     /// with a builder pattern.
@@ -368,6 +647,7 @@ impl PgPass {
     pub fn query(&self) -> QueryBuilder<'_> {
         QueryBuilder {
             query: Default::default(),
+            match_policy: self.settings.match_policy,
             pgpass: self,
         }
     }
@@ -380,15 +660,63 @@ impl FromStr for PgPass {
     }
 }
 
+/// The line each pattern was read from (starting from 1), or `None` if it was
+/// added programmatically. This is provenance rather than content, so it is
+/// ignored when comparing or hashing a [`PgPass`].
+#[derive(Debug, Clone, Default)]
+struct LineNumbers(Vec<Option<usize>>);
+impl PartialEq for LineNumbers {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+impl Eq for LineNumbers {}
+impl PartialOrd for LineNumbers {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for LineNumbers {
+    fn cmp(&self, _other: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
+impl Hash for LineNumbers {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
 /// A more ergonomic interface to [`PgPass::find`]. See [`PgPass::query`].
 #[derive(Debug, Clone)]
 pub struct QueryBuilder<'a> {
     query: CredentialQuery,
+    match_policy: MatchPolicy,
     pgpass: &'a PgPass,
 }
 impl QueryBuilder<'_> {
-    pub fn find(self) -> Result<Option<Credentials>, IncompleteCredential> {
-        self.pgpass.find(&self.query)
+    pub fn find(self) -> Result<Option<Credentials>, FindError> {
+        self.pgpass.find_with_policy(&self.query, self.match_policy)
+    }
+    /// Refuse to use patterns with a wildcard hostname for this query, regardless
+    /// of the [`MatchPolicy`] set on the [`PgPass`]. Use this when the hostname
+    /// comes from an untrusted source, such as a command line argument.
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let pgpass: PgPass = "*:*:*:*:secret".parse()?;
+    /// let actual = pgpass.query()
+    ///     .hostname("attacker.example.com")?
+    ///     .database("my_database")?
+    ///     .username("username")?
+    ///     .strict_host()
+    ///     .find();
+    /// assert_eq!(actual, Err(FindError::WildcardHostRefused { index: 0 }));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn strict_host(mut self) -> Self {
+        self.match_policy = MatchPolicy::StrictHost;
+        self
     }
     pub fn build(self) -> CredentialQuery {
         self.query
@@ -411,8 +739,10 @@ impl QueryBuilder<'_> {
     }
 }
 
-/// An error encountered while reading a pgpass file.
+/// An error encountered while reading a pgpass file. Some variants depend on
+/// which features are enabled, so more may be added without a major version.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum LoadError {
     /// The pgpass file was invalid. It is safe to log or display this error;
     /// it will not contains passwords. (Broken escape sequences are displayed,
@@ -425,9 +755,67 @@ pub enum LoadError {
     /// We encountered an I/O error while processing the file.
     #[error("{0}")]
     Io(#[from] io::Error),
-    /// The file did not contain valid UTF8.
+    /// The file did not contain valid UTF8. The error's
+    /// [`valid_up_to`][str::Utf8Error::valid_up_to] is relative to the start of
+    /// the file, or of the line when reading one line at a time.
+    #[error("Line {line}: {source}")]
+    Utf8 { line: usize, source: str::Utf8Error },
+    /// A line of the file was invalid. This is only returned when reading a file
+    /// one line at a time (see [`stream`]). Like
+    /// [`SyntaxError`][LoadError::SyntaxError], it is safe to log or display.
+    #[error("Line {line}: {source}")]
+    InvalidLine { line: usize, source: ParsingError },
+    /// The file was larger than [`LoadOptions::max_file_size`] bytes.
+    #[error("The file is larger than the limit of {limit} bytes.")]
+    FileTooLarge { limit: u64 },
+    /// A line was longer than [`LoadOptions::max_line_length`] bytes.
+    #[error("Line {line} is longer than the limit of {limit} bytes.")]
+    LineTooLong { line: usize, limit: usize },
+    /// The file had more than [`LoadOptions::max_entries`] patterns.
+    #[error("The file contains more than the limit of {limit} entries.")]
+    TooManyEntries { limit: usize },
+    /// An [`encrypted`] file could not be decrypted.
+    #[cfg(feature = "encryption")]
+    #[error("Failed to decrypt the file: {0}")]
+    Decrypt(#[from] age::DecryptError),
+}
+
+/// A possible location of the pgpass file. See [`PgPass::locate`].
+enum Candidate {
+    /// Given by `PGPASSFILE`; this is used even if it does not exist.
+    Environment(PathBuf),
+    /// The default location, which is only used if it is a file.
+    Default(PathBuf),
+}
+
+/// The line (starting from 1) containing the byte at `offset`.
+fn line_of(contents: &[u8], offset: usize) -> usize {
+    1 + contents[..offset].iter().filter(|b| **b == b'\n').count()
+}
+
+/// An error encountered while querying [`PgPass`] for [credentials][Credentials].
+/// It is safe to log or display this error; it will not contain passwords. Some
+/// variants depend on which features are enabled, so more may be added without
+/// a major version.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FindError {
+    /// A pattern matched, but we were missing a required value.
+    #[error("{0}")]
+    Incomplete(#[from] IncompleteCredential),
+    /// A pattern matched, but it's hostname was a wildcard and the
+    /// [`MatchPolicy`] does not allow these patterns to be used.
+    #[error("Refused to use entry {index}: it's hostname is a wildcard.")]
+    WildcardHostRefused { index: usize },
+    /// A pattern matched, but the [`CredentialPolicy`] does not allow credentials
+    /// to be released to the host.
+    #[error("Refused to release credentials for '{hostname}': the host is not allowed.")]
+    HostNotAllowed { hostname: String },
+    /// A pattern matched, but it's [encrypted password][encrypted_field] could
+    /// not be decrypted.
+    #[cfg(feature = "field-encryption")]
     #[error("{0}")]
-    Utf8(#[from] str::Utf8Error),
+    Field(#[from] encrypted_field::FieldError),
 }
 
 /// An error encountered while querying [`PgPass`] for [credentials][Credentials].
@@ -450,6 +838,53 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn byte_order_mark_is_ignored() -> anyhow::Result<()> {
+        let s = "localhost:*:*:*:password\n";
+        let with_bom = [BOM, s.as_bytes()].concat();
+        assert_eq!(PgPass::read(&with_bom[..])?, PgPass::read(s.as_bytes())?);
+        assert_eq!(PgPass::read(&with_bom[..])?.line(0), Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn leading_byte_order_mark_round_trips() -> anyhow::Result<()> {
+        let mut pgpass = PgPass::default();
+        pgpass.add(
+            CredentialPattern::default()
+                .hostname("\u{FEFF}host")?
+                .password("\u{FEFF}password")?,
+        );
+        pgpass.add(
+            CredentialPattern::default()
+                .hostname("\u{FEFF}second")?
+                .password("password")?,
+        );
+        let mut buf = Vec::new();
+        pgpass.save_into(&mut buf)?;
+        // Only the first hostname needs another byte order mark
+        let expected = "\u{FEFF}host:*:*:*:\u{FEFF}password\n\u{FEFF}second:*:*:*:password\n";
+        assert_eq!(buf, [BOM, expected.as_bytes()].concat());
+        assert_eq!(PgPass::read(&buf[..])?, pgpass);
+        assert_eq!(str::from_utf8(&buf)?.parse::<PgPass>()?, pgpass);
+        assert_eq!(PgPass::try_from(raw::RawPgPass::read(&buf[..])?)?, pgpass);
+
+        Ok(())
+    }
+
+    #[test]
+    fn utf8_errors_report_the_line() {
+        let s = b"# Comment\nlocalhost:*:*:*:one\n*:*:*:*:caf\xe9\n";
+        match PgPass::read(&s[..]) {
+            Err(LoadError::Utf8 { line, source }) => {
+                assert_eq!(line, 3);
+                assert_eq!(source.valid_up_to(), 41);
+            }
+            other => panic!("expected a UTF-8 error, got {:?}", other),
+        }
+    }
+
     #[test]
     fn simple_find() -> anyhow::Result<()> {
         let expected = CredentialPattern::default()
@@ -696,7 +1131,7 @@ mod tests {
             .with(d.clone());
 
         let actual = pgpass.query().port(65535)?.find();
-        assert_eq!(Err(IncompleteCredential::MissingHostname), actual);
+        assert_eq!(Err(IncompleteCredential::MissingHostname.into()), actual);
 
         let expected = a.clone().hostname("this_hostname")?;
         let actual = pgpass
@@ -713,7 +1148,7 @@ mod tests {
         assert_eq!(expected, actual);
 
         let actual = pgpass.query().username("other_username")?.find();
-        assert_eq!(Err(IncompleteCredential::MissingDatabase), actual);
+        assert_eq!(Err(IncompleteCredential::MissingDatabase.into()), actual);
 
         let expected = c.clone().database("this_database")?;
         let actual = pgpass
@@ -725,7 +1160,7 @@ mod tests {
         assert_eq!(expected, actual);
 
         let actual = pgpass.query().hostname("other_hostname")?.port(123)?.find();
-        assert_eq!(Err(IncompleteCredential::MissingUsername), actual);
+        assert_eq!(Err(IncompleteCredential::MissingUsername.into()), actual);
 
         let expected = d.clone().username("this_username")?;
         let actual = pgpass
@@ -762,6 +1197,23 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn insert_and_retain_keep_lines() -> anyhow::Result<()> {
+        let mut pgpass: PgPass = "one:*:*:*:1\n# comment\ntwo:*:*:*:2".parse()?;
+        pgpass.insert(1, CredentialPattern::default().password("inserted")?);
+        assert_eq!(
+            (pgpass.line(0), pgpass.line(1), pgpass.line(2)),
+            (Some(1), None, Some(3))
+        );
+
+        pgpass.retain(|pattern| pattern.password != "1");
+        assert_eq!(pgpass.patterns.len(), 2);
+        assert_eq!(pgpass.patterns[0].password, "inserted");
+        assert_eq!((pgpass.line(0), pgpass.line(1)), (None, Some(3)));
+
+        Ok(())
+    }
+
     #[test]
     fn unrecognized_column() {
         let actual = "one:2:three:four:five:six".parse::<PgPass>().err();
@@ -770,6 +1222,7 @@ mod tests {
 }
 
 #[cfg(test)]
+#[allow(clippy::single_match, clippy::collapsible_match)]
 mod property_tests {
     use super::*;
     use proptest::prelude::*;
@@ -779,6 +1232,43 @@ mod property_tests {
     };
     const VALID_FIELD: &str = "[^\r\n#]+";
     const ARBITRARY_FIELD: &str = ".+";
+    // A small alphabet, so that patterns and queries frequently match
+    const SMALL_FIELD: &str = "[ab]";
+
+    fn small_pattern() -> impl Strategy<Value = CredentialPattern<HasPasswordTrue>> {
+        (
+            prop::option::of(SMALL_FIELD),
+            prop::option::of(1..3u16),
+            prop::option::of(SMALL_FIELD),
+            prop::option::of(SMALL_FIELD),
+            VALID_FIELD,
+        )
+            .prop_map(
+                |(hostname, port, database, username, password)| CredentialPattern {
+                    hostname,
+                    port: port.and_then(NonZeroU16::new),
+                    database,
+                    username,
+                    password,
+                    _tag: PhantomData,
+                },
+            )
+    }
+
+    fn small_query() -> impl Strategy<Value = CredentialQuery> {
+        (
+            prop::option::of(SMALL_FIELD),
+            prop::option::of(1..3u16),
+            prop::option::of(SMALL_FIELD),
+            prop::option::of(SMALL_FIELD),
+        )
+            .prop_map(|(hostname, port, database, username)| CredentialQuery {
+                hostname,
+                port: port.and_then(NonZeroU16::new),
+                database,
+                username,
+            })
+    }
 
     proptest! {
         #[test]
@@ -801,6 +1291,7 @@ mod property_tests {
                             .username(username).unwrap()
                             .password(password).unwrap()
                     }).collect(),
+                ..Default::default()
             };
 
             let mut f: Cursor<Vec<u8>> = Default::default();
@@ -838,6 +1329,7 @@ mod property_tests {
                             _tag: PhantomData
                     }
                     }).collect(),
+                ..Default::default()
             };
 
             let mut f: Cursor<Vec<u8>> = Default::default();
@@ -860,6 +1352,37 @@ mod property_tests {
             }
         }
 
+        #[test]
+        fn sorting_by_specificity_agrees_with_most_specific(
+            patterns in prop::collection::vec(small_pattern(), 0..10),
+            query in small_query(),
+        ) {
+            let pgpass = PgPass { patterns, ..Default::default() };
+            let mut sorted = pgpass.clone();
+            sorted.sort_by_specificity();
+            let pgpass = pgpass.with_match_strategy(MatchStrategy::MostSpecific);
+
+            assert_eq!(pgpass.find(&query), sorted.find(&query));
+        }
+
+        #[test]
+        fn index_agrees_with_find(
+            patterns in prop::collection::vec(small_pattern(), 0..20),
+            query in small_query(),
+            strategy in prop_oneof![
+                Just(MatchStrategy::FirstMatch),
+                Just(MatchStrategy::MostSpecific)
+            ],
+            policy in prop_oneof![Just(MatchPolicy::Permissive), Just(MatchPolicy::StrictHost)],
+        ) {
+            let pgpass = PgPass { patterns, ..Default::default() }
+                .with_match_strategy(strategy)
+                .with_match_policy(policy);
+            let index = PgPassIndex::new(pgpass.clone());
+
+            assert_eq!(pgpass.find(&query), index.find(&query));
+        }
+
         #[test]
         fn no_unknown_parsing_errors_on_trash(input in ".*") {
             // Test against completely arbitrary files
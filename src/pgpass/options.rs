@@ -0,0 +1,212 @@
+//! Limits applied while loading a pgpass file. See [`LoadOptions`].
+
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{LoadError, BOM};
+
+/// Limits enforced while reading a pgpass file, so that a hostile or mistaken
+/// path (such as `PGPASSFILE=/dev/zero`) cannot exhaust memory. Exceeding a limit
+/// returns a [`LoadError`] rather than continuing to read.
+///
+/// [`PgPass::read`][super::PgPass::read], [`open`][super::PgPass::open] and
+/// [`load`][super::PgPass::load] use the [default](LoadOptions::default) limits,
+/// which are far larger than any reasonable pgpass file. `None` disables a limit.
+///
+/// ```
+/// # use postgres_secrets::pgpass::*;
+/// # fn main() -> anyhow::Result<()> {
+/// let options = LoadOptions::default().with_max_entries(Some(1));
+/// let file = "a:*:*:*:one\nb:*:*:*:two\n";
+/// assert!(matches!(
+///     PgPass::read_with(file.as_bytes(), &options),
+///     Err(LoadError::TooManyEntries { limit: 1 })
+/// ));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LoadOptions {
+    max_file_size: Option<u64>,
+    max_line_length: Option<usize>,
+    max_entries: Option<usize>,
+}
+impl LoadOptions {
+    /// 16 MiB.
+    pub const DEFAULT_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+    /// 64 KiB.
+    pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+    pub const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+    /// Options with no limits. Only use this for trusted input.
+    pub fn unlimited() -> Self {
+        Self {
+            max_file_size: None,
+            max_line_length: None,
+            max_entries: None,
+        }
+    }
+    /// The maximum number of bytes read from the file.
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+    /// The maximum number of bytes in a line, excluding the line ending.
+    pub fn max_line_length(&self) -> Option<usize> {
+        self.max_line_length
+    }
+    /// The maximum number of patterns in the file. Comments and blank lines are
+    /// not counted.
+    pub fn max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+    pub fn set_max_file_size(&mut self, limit: Option<u64>) {
+        self.max_file_size = limit
+    }
+    /// Builder interface to [`set_max_file_size`][LoadOptions::set_max_file_size].
+    pub fn with_max_file_size(mut self, limit: Option<u64>) -> Self {
+        self.set_max_file_size(limit);
+        self
+    }
+    pub fn set_max_line_length(&mut self, limit: Option<usize>) {
+        self.max_line_length = limit
+    }
+    /// Builder interface to [`set_max_line_length`][LoadOptions::set_max_line_length].
+    pub fn with_max_line_length(mut self, limit: Option<usize>) -> Self {
+        self.set_max_line_length(limit);
+        self
+    }
+    pub fn set_max_entries(&mut self, limit: Option<usize>) {
+        self.max_entries = limit
+    }
+    /// Builder interface to [`set_max_entries`][LoadOptions::set_max_entries].
+    pub fn with_max_entries(mut self, limit: Option<usize>) -> Self {
+        self.set_max_entries(limit);
+        self
+    }
+}
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            max_file_size: Some(Self::DEFAULT_MAX_FILE_SIZE),
+            max_line_length: Some(Self::DEFAULT_MAX_LINE_LENGTH),
+            max_entries: Some(Self::DEFAULT_MAX_ENTRIES),
+        }
+    }
+}
+
+impl LoadOptions {
+    /// Read the whole file, stopping once it exceeds [`max_file_size`][a]. A
+    /// leading byte order mark is removed.
+    ///
+    /// [a]: LoadOptions::max_file_size
+    pub(crate) fn read_limited<F: Read>(&self, f: F) -> Result<Vec<u8>, LoadError> {
+        let mut contents = Vec::with_capacity(8192);
+        f.take(self.read_budget()).read_to_end(&mut contents)?;
+        self.check_read(&mut contents)?;
+        Ok(contents)
+    }
+    /// The async equivalent of [`read_limited`][LoadOptions::read_limited].
+    #[cfg(feature = "async")]
+    pub(crate) async fn read_limited_async<F: AsyncRead + Unpin>(
+        &self,
+        f: F,
+    ) -> Result<Vec<u8>, LoadError> {
+        let mut contents = Vec::with_capacity(8192);
+        f.take(self.read_budget())
+            .read_to_end(&mut contents)
+            .await?;
+        self.check_read(&mut contents)?;
+        Ok(contents)
+    }
+    /// How many bytes to read. This is one more than the limit, so that we can
+    /// tell if it was exceeded.
+    pub(crate) fn read_budget(&self) -> u64 {
+        self.max_file_size
+            .map_or(u64::MAX, |limit| limit.saturating_add(1))
+    }
+    /// Check the size of a file read within the [budget][LoadOptions::read_budget],
+    /// and remove it's byte order mark.
+    pub(crate) fn check_read(&self, contents: &mut Vec<u8>) -> Result<(), LoadError> {
+        if let Some(limit) = self.max_file_size {
+            if contents.len() as u64 > limit {
+                return Err(LoadError::FileTooLarge { limit });
+            }
+        }
+        if contents.starts_with(BOM) {
+            contents.drain(..BOM.len());
+        }
+        Ok(())
+    }
+    /// Check every line of a file against [`max_line_length`][a].
+    ///
+    /// [a]: LoadOptions::max_line_length
+    pub(crate) fn check_line_lengths(&self, contents: &[u8]) -> Result<(), LoadError> {
+        let Some(limit) = self.max_line_length else {
+            return Ok(());
+        };
+        for (i, line) in contents.split(|b| *b == b'\n').enumerate() {
+            if line.strip_suffix(b"\r").unwrap_or(line).len() > limit {
+                return Err(LoadError::LineTooLong { line: i + 1, limit });
+            }
+        }
+        Ok(())
+    }
+    /// Check the number of patterns read so far against [`max_entries`][a].
+    ///
+    /// [a]: LoadOptions::max_entries
+    pub(crate) fn check_entries(&self, entries: usize) -> Result<(), LoadError> {
+        match self.max_entries {
+            Some(limit) if entries > limit => Err(LoadError::TooManyEntries { limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::PgPass;
+    use std::io;
+
+    #[test]
+    fn endless_file() {
+        let options = LoadOptions::default().with_max_file_size(Some(1024 * 1024));
+        assert!(matches!(
+            PgPass::read_with(io::repeat(0), &options),
+            Err(LoadError::FileTooLarge { limit: 1048576 })
+        ));
+    }
+
+    #[test]
+    fn limits() -> anyhow::Result<()> {
+        let s = "a:*:*:*:one\r\n# A long comment\nb:*:*:*:two\n";
+        let read = |options: LoadOptions| PgPass::read_with(s.as_bytes(), &options);
+
+        let pgpass = read(LoadOptions::unlimited())?;
+        assert_eq!(pgpass, s.parse()?);
+        assert_eq!(pgpass.line(1), Some(3));
+        assert_eq!(read(LoadOptions::default())?, pgpass);
+
+        assert!(read(LoadOptions::unlimited().with_max_file_size(Some(42))).is_ok());
+        assert!(matches!(
+            read(LoadOptions::unlimited().with_max_file_size(Some(41))),
+            Err(LoadError::FileTooLarge { limit: 41 })
+        ));
+
+        assert!(read(LoadOptions::unlimited().with_max_line_length(Some(16))).is_ok());
+        assert!(matches!(
+            read(LoadOptions::unlimited().with_max_line_length(Some(15))),
+            Err(LoadError::LineTooLong { line: 2, limit: 15 })
+        ));
+
+        assert!(read(LoadOptions::unlimited().with_max_entries(Some(2))).is_ok());
+        assert!(matches!(
+            read(LoadOptions::unlimited().with_max_entries(Some(1))),
+            Err(LoadError::TooManyEntries { limit: 1 })
+        ));
+
+        Ok(())
+    }
+}
@@ -1,11 +1,11 @@
-use std::{marker::PhantomData, num::NonZeroU16};
+use std::{borrow::Cow, num::NonZeroU16};
 
 use nom::{
     bytes::complete::tag, character::complete::line_ending, combinator::opt, sequence::Tuple,
     Err as NomErr, IResult, Parser,
 };
 
-use crate::pgpass::{pattern::HasPasswordTrue, CredentialPattern, DELIMITER};
+use crate::pgpass::{pattern::BorrowedCredentialPattern, DELIMITER};
 
 use super::{
     field::{field, required_field, FieldError},
@@ -21,7 +21,7 @@ fn field_delimiter(s: &str) -> IResult<&str, (), FieldError> {
     }
 }
 
-fn hostname_field(s: &str) -> IResult<&str, Option<String>, ParsingError> {
+fn hostname_field(s: &str) -> IResult<&str, Option<Cow<'_, str>>, ParsingError> {
     let (remaining, (hostname, _)) = match (field, field_delimiter).parse(s) {
         Ok(x) => x,
         Err(NomErr::Error(e)) => return Err(NomErr::Error(ParsingError::InvalidHostname(e))),
@@ -40,7 +40,7 @@ fn port_field(s: &str) -> IResult<&str, Option<NonZeroU16>, ParsingError> {
     }
 }
 
-fn database_field(s: &str) -> IResult<&str, Option<String>, ParsingError> {
+fn database_field(s: &str) -> IResult<&str, Option<Cow<'_, str>>, ParsingError> {
     let (remaining, (database, _)) = match (field, field_delimiter).parse(s) {
         Ok(x) => x,
         Err(NomErr::Error(e)) => return Err(NomErr::Error(ParsingError::InvalidDatabase(e))),
@@ -50,7 +50,7 @@ fn database_field(s: &str) -> IResult<&str, Option<String>, ParsingError> {
     Ok((remaining, database))
 }
 
-fn username_field(s: &str) -> IResult<&str, Option<String>, ParsingError> {
+fn username_field(s: &str) -> IResult<&str, Option<Cow<'_, str>>, ParsingError> {
     let (remaining, (username, _)) = match (field, field_delimiter).parse(s) {
         Ok(x) => x,
         Err(NomErr::Error(e)) => return Err(NomErr::Error(ParsingError::InvalidUsername(e))),
@@ -60,7 +60,7 @@ fn username_field(s: &str) -> IResult<&str, Option<String>, ParsingError> {
     Ok((remaining, username))
 }
 
-fn password_field(s: &str) -> IResult<&str, String, ParsingError> {
+fn password_field(s: &str) -> IResult<&str, Cow<'_, str>, ParsingError> {
     let (remaining, (password, delim, _)) =
         match (required_field, opt(field_delimiter), opt(line_ending)).parse(s) {
             Ok(x) => x,
@@ -74,9 +74,9 @@ fn password_field(s: &str) -> IResult<&str, String, ParsingError> {
     }
 }
 
-pub fn credential_pattern(
+pub fn borrowed_credential_pattern(
     s: &str,
-) -> IResult<&str, CredentialPattern<HasPasswordTrue>, ParsingError> {
+) -> IResult<&str, BorrowedCredentialPattern<'_>, ParsingError> {
     let (remaining, (hostname, port, database, username, password)) = (
         hostname_field,
         port_field,
@@ -88,13 +88,12 @@ pub fn credential_pattern(
 
     Ok((
         remaining,
-        CredentialPattern {
+        BorrowedCredentialPattern {
             hostname,
             port,
             database,
             username,
             password,
-            _tag: PhantomData,
         },
     ))
 }
@@ -102,11 +101,19 @@ pub fn credential_pattern(
 #[cfg(test)]
 mod test {
     use nom::Finish;
+    use std::marker::PhantomData;
 
-    use crate::pgpass::parser::port::PortError;
+    use crate::pgpass::{parser::port::PortError, pattern::HasPasswordTrue, CredentialPattern};
 
     use super::*;
 
+    fn credential_pattern(
+        s: &str,
+    ) -> IResult<&str, CredentialPattern<HasPasswordTrue>, ParsingError> {
+        let (remaining, pattern) = borrowed_credential_pattern(s)?;
+        Ok((remaining, pattern.into_owned()))
+    }
+
     #[test]
     fn simple() {
         let s = "one:2:three:four:five";
@@ -121,6 +128,24 @@ mod test {
         assert_eq!(credential_pattern(s).unwrap(), ("", expected.clone()));
     }
 
+    #[test]
+    fn borrowed() {
+        let s = "one:2:th\\:ree:four:five\nabc";
+        let expected = BorrowedCredentialPattern {
+            hostname: Some(Cow::Borrowed("one")),
+            port: Some(NonZeroU16::new(2).unwrap()),
+            database: Some(Cow::Owned("th:ree".to_string())),
+            username: Some(Cow::Borrowed("four")),
+            password: Cow::Borrowed("five"),
+        };
+        let (remaining, actual) = borrowed_credential_pattern(s).unwrap();
+        assert_eq!(remaining, "abc");
+        assert_eq!(actual, expected);
+        assert!(matches!(actual.hostname, Some(Cow::Borrowed(_))));
+        assert!(matches!(actual.database, Some(Cow::Owned(_))));
+        assert!(matches!(actual.password, Cow::Borrowed(_)));
+    }
+
     #[test]
     fn newlines_are_ignored() {
         let s = "one:2:three:four:five";
@@ -1,8 +1,9 @@
+use std::borrow::Cow;
+
 use nom::{
     branch::alt,
-    bytes::complete::{escaped_transform, tag},
+    bytes::complete::{escaped, tag},
     character::complete::none_of,
-    combinator::value,
     error::{ErrorKind, ParseError},
     Err as NomErr, IResult, Parser,
 };
@@ -14,24 +15,42 @@ pub fn wildcard(s: &str) -> IResult<&str, ()> {
     Ok((remaining, ()))
 }
 
-pub fn field_value(s: &str) -> IResult<&str, String, FieldError> {
+/// Parses a field, borrowing it from the input unless it contains escape sequences.
+pub fn field_value(s: &str) -> IResult<&str, Cow<'_, str>, FieldError> {
     if s.is_empty() || s.starts_with(DELIMITER) {
         return Err(NomErr::Error(FieldError::Empty));
     };
 
-    escaped_transform(
+    let (remaining, raw) = escaped(
         none_of("\\:*\r\n"),
         ESCAPE_CHAR,
-        alt((
-            value(ESCAPE, tag(ESCAPE)),
-            value(DELIMITER, tag(DELIMITER)),
-            value(WILDCARD, tag(WILDCARD)),
-        )),
+        alt((tag(ESCAPE), tag(DELIMITER), tag(WILDCARD))),
     )
-    .parse(s)
+    .parse(s)?;
+
+    if raw.contains(ESCAPE_CHAR) {
+        Ok((remaining, Cow::Owned(unescape(raw))))
+    } else {
+        Ok((remaining, Cow::Borrowed(raw)))
+    }
+}
+
+/// Remove the escape characters from a field. The escape sequences must already
+/// have been validated.
+fn unescape(raw: &str) -> String {
+    let mut output = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c == ESCAPE_CHAR {
+            output.extend(chars.next());
+        } else {
+            output.push(c);
+        }
+    }
+    output
 }
 
-pub fn field(s: &str) -> IResult<&str, Option<String>, FieldError> {
+pub fn field(s: &str) -> IResult<&str, Option<Cow<'_, str>>, FieldError> {
     if let Ok((remaining, _)) = wildcard.parse(s) {
         Ok((remaining, None))
     } else {
@@ -40,7 +59,7 @@ pub fn field(s: &str) -> IResult<&str, Option<String>, FieldError> {
     }
 }
 
-pub fn required_field(s: &str) -> IResult<&str, String, FieldError> {
+pub fn required_field(s: &str) -> IResult<&str, Cow<'_, str>, FieldError> {
     if let Ok((_remaining, _)) = wildcard.parse(s) {
         return Err(NomErr::Error(FieldError::Required));
     }
@@ -76,7 +95,7 @@ impl ParseError<&str> for FieldError {
     fn from_error_kind(_input: &str, kind: ErrorKind) -> Self {
         // We do NOT store the input. Otherwise, we may accidentally expose
         // passwords in logs.
-        if kind == ErrorKind::EscapedTransform {
+        if kind == ErrorKind::Escaped {
             Self::InvalidEscapeNoChar
         } else {
             Self::Unknown(kind)
@@ -112,17 +131,26 @@ mod test {
     #[test]
     fn simple_field() {
         let s = "abc:def";
-        let expected = (":def", Some("abc".to_string()));
+        let expected = (":def", Some("abc".into()));
         assert_eq!(field.parse(s).unwrap(), expected);
     }
 
     #[test]
     fn simple_required_field() {
         let s = "abc:def";
-        let expected = (":def", "abc".to_string());
+        let expected = (":def", "abc".into());
         assert_eq!(required_field.parse(s).unwrap(), expected);
     }
 
+    #[test]
+    fn borrowed_unless_escaped() {
+        let (_, actual) = field_value("abc:def").unwrap();
+        assert!(matches!(actual, Cow::Borrowed("abc")));
+
+        let (_, actual) = field_value("a\\:bc:def").unwrap();
+        assert!(matches!(actual, Cow::Owned(ref s) if s == "a:bc"));
+    }
+
     #[test]
     fn wildcard_yields_none() {
         let s = "*:def";
@@ -133,21 +161,21 @@ mod test {
     #[test]
     fn escape_delimiter() {
         let s = "abc\\::def";
-        let expected = (":def", Some("abc:".to_string()));
+        let expected = (":def", Some("abc:".into()));
         assert_eq!(field.parse(s).unwrap(), expected);
     }
 
     #[test]
     fn escape_wildcard() {
         let s = "abc\\*:def";
-        let expected = (":def", Some("abc*".to_string()));
+        let expected = (":def", Some("abc*".into()));
         assert_eq!(field.parse(s).unwrap(), expected);
     }
 
     #[test]
     fn escape_escape_char() {
         let s = "abc\\\\:def";
-        let expected = (":def", Some("abc\\".to_string()));
+        let expected = (":def", Some("abc\\".into()));
         assert_eq!(field.parse(s).unwrap(), expected);
     }
 
@@ -200,6 +228,13 @@ mod test {
             unreachable!()
         };
         assert_eq!(actual, expected);
+
+        let s = "\\\u{FEFF}abc";
+        let expected = FieldError::InvalidEscape('\u{FEFF}');
+        let NomErr::Error(actual) = field(s).err().unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(actual, expected);
     }
 
     #[test]
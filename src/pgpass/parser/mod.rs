@@ -3,10 +3,14 @@ use nom::{
     Finish, Parser,
 };
 
-use crate::PgPass;
+use crate::{
+    pgpass::{pattern::BorrowedCredentialPattern, LineNumbers, BOM_CHAR},
+    PgPass,
+};
 
 use self::{
-    credential_pattern::credential_pattern, field::FieldError, ignored::ignored, port::PortError,
+    credential_pattern::borrowed_credential_pattern, field::FieldError, ignored::ignored,
+    port::PortError,
 };
 
 pub mod credential_pattern;
@@ -15,19 +19,57 @@ pub mod ignored;
 pub mod port;
 
 pub fn pgpass(s: &str) -> Result<PgPass, ParsingError> {
+    let s = strip_bom(s);
+    let mut patterns = Vec::with_capacity(8);
+    let mut lines = Vec::with_capacity(8);
+    for_each_pattern(s, |pattern, line| {
+        patterns.push(pattern.into_owned());
+        lines.push(Some(line));
+        Ok::<_, ParsingError>(())
+    })?;
+
+    Ok(PgPass {
+        patterns,
+        lines: LineNumbers(lines),
+        ..Default::default()
+    })
+}
+
+pub fn pgpass_borrowed(s: &str) -> Result<Vec<BorrowedCredentialPattern<'_>>, ParsingError> {
+    let s = strip_bom(s);
     let mut patterns = Vec::with_capacity(8);
+    for_each_pattern(s, |pattern, _| {
+        patterns.push(pattern);
+        Ok::<_, ParsingError>(())
+    })?;
+    Ok(patterns)
+}
+
+/// Remove a leading byte order mark, which some editors on Windows add.
+pub fn strip_bom(s: &str) -> &str {
+    s.strip_prefix(BOM_CHAR).unwrap_or(s)
+}
+
+/// Parse each pattern in the file, passing it to `f` along with the line it was
+/// found on (starting from 1). Parsing stops at the first error from `f`.
+pub fn for_each_pattern<'a, E: From<ParsingError>>(
+    s: &'a str,
+    mut f: impl FnMut(BorrowedCredentialPattern<'a>, usize) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut line = 1;
     let mut remaining = s;
     while !remaining.is_empty() {
-        if let Ok((r, _)) = ignored.parse(remaining) {
-            remaining = r;
+        let r = if let Ok((r, _)) = ignored.parse(remaining) {
+            r
         } else {
-            let (r, pattern) = credential_pattern(remaining).finish()?;
-            remaining = r;
-            patterns.push(pattern);
-        }
+            let (r, pattern) = borrowed_credential_pattern(remaining).finish()?;
+            f(pattern, line)?;
+            r
+        };
+        line += remaining[..remaining.len() - r.len()].matches('\n').count();
+        remaining = r;
     }
-
-    Ok(PgPass { patterns })
+    Ok(())
 }
 
 /// An error encountered when parsing an invalid pgpass file.
@@ -110,6 +152,35 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn line_numbers() {
+        let s = "# Comment\none:2:three:four:five\n\r\n# Comment\na:1:b:c:d\n";
+        let actual = pgpass(s).unwrap();
+        assert_eq!(actual.line(0), Some(2));
+        assert_eq!(actual.line(1), Some(5));
+        assert_eq!(actual.line(2), None);
+    }
+
+    #[test]
+    fn borrowed_agrees_with_owned() {
+        let s = "# Comment\none:2:th\\:ree:four:five\n\r\n*:*:b:c:d\\\\\n";
+        let mut owned = PgPass::default();
+        for pattern in pgpass_borrowed(s).unwrap() {
+            owned.add(pattern.into_owned());
+        }
+        assert_eq!(owned, pgpass(s).unwrap());
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        let s = "one:2:three:four:five\na:1:b:c:d";
+        let bom = format!("{}{}", BOM_CHAR, s);
+        assert_eq!(pgpass(&bom), pgpass(s));
+        assert_eq!(pgpass_borrowed(&bom), pgpass_borrowed(s));
+        // Only at the start of the file
+        assert!(pgpass(&format!("{}\n{}", s, BOM_CHAR)).is_err());
+    }
+
     #[test]
     fn either_linebreak_convention_works() {
         let s1 = "one:2:three:four:five\na:1:b:c:d";
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::{marker::PhantomData, num::NonZeroU16};
+use std::{borrow::Cow, fmt::Display, marker::PhantomData, num::NonZeroU16};
 
 use crate::{Credentials, DEFAULT_PORT};
 
@@ -99,6 +99,62 @@ impl<HasPassword> CredentialPattern<HasPassword> {
             })
         }
     }
+    /// Returns true if the pattern matches the query. A wildcard (in either the
+    /// pattern or the query) matches any value.
+    pub fn matches(&self, query: &CredentialQuery) -> bool {
+        self.mismatch(query).is_none()
+    }
+    /// The first field which prevents the pattern from matching the query, if any.
+    pub(crate) fn mismatch(&self, query: &CredentialQuery) -> Option<Field> {
+        fn differs<T: PartialEq>(pattern: Option<&T>, query: Option<&T>) -> bool {
+            pattern
+                .zip(query)
+                .is_some_and(|(pattern, query)| pattern != query)
+        }
+
+        if differs(self.hostname.as_ref(), query.hostname.as_ref()) {
+            Some(Field::Hostname)
+        } else if differs(self.port.as_ref(), query.port.as_ref()) {
+            Some(Field::Port)
+        } else if differs(self.database.as_ref(), query.database.as_ref()) {
+            Some(Field::Database)
+        } else if differs(self.username.as_ref(), query.username.as_ref()) {
+            Some(Field::Username)
+        } else {
+            None
+        }
+    }
+    /// The number of fields which are not wildcards (from 0 to 4). Used by
+    /// [`MatchStrategy::MostSpecific`][super::MatchStrategy::MostSpecific].
+    pub fn specificity(&self) -> usize {
+        [
+            self.hostname.is_some(),
+            self.port.is_some(),
+            self.database.is_some(),
+            self.username.is_some(),
+        ]
+        .into_iter()
+        .filter(|concrete| *concrete)
+        .count()
+    }
+    /// The fields of the pattern which are wildcards, in file order. The password
+    /// is never a wildcard, and so is never included.
+    pub fn wildcards(&self) -> Vec<Field> {
+        let mut wildcards = Vec::with_capacity(Field::ALL.len());
+        if self.hostname.is_none() {
+            wildcards.push(Field::Hostname);
+        }
+        if self.port.is_none() {
+            wildcards.push(Field::Port);
+        }
+        if self.database.is_none() {
+            wildcards.push(Field::Database);
+        }
+        if self.username.is_none() {
+            wildcards.push(Field::Username);
+        }
+        wildcards
+    }
     pub(crate) fn capacity_needed(&self) -> usize {
         let hostname_cap = self
             .hostname
@@ -238,6 +294,51 @@ impl<T> PartialEq<Credentials> for CredentialPattern<T> {
     }
 }
 
+/// A row of a pgpass file which borrows from the text it was parsed from. Fields
+/// without escape sequences point into the input, so parsing them does not
+/// allocate. See [`PgPass::parse_borrowed`][super::PgPass::parse_borrowed].
+///
+/// Use [`into_owned`][BorrowedCredentialPattern::into_owned] to convert it into
+/// a [`CredentialPattern`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BorrowedCredentialPattern<'a> {
+    pub hostname: Option<Cow<'a, str>>,
+    pub port: Option<NonZeroU16>,
+    pub database: Option<Cow<'a, str>>,
+    pub username: Option<Cow<'a, str>>,
+    pub password: Cow<'a, str>,
+}
+impl BorrowedCredentialPattern<'_> {
+    /// Returns true if the pattern matches the query. See [`CredentialPattern::matches`].
+    pub fn matches(&self, query: &CredentialQuery) -> bool {
+        fn differs(pattern: Option<&Cow<'_, str>>, query: Option<&String>) -> bool {
+            pattern
+                .zip(query)
+                .is_some_and(|(pattern, query)| pattern != query)
+        }
+
+        !(differs(self.hostname.as_ref(), query.hostname.as_ref())
+            || self.port.zip(query.port).is_some_and(|(p, q)| p != q)
+            || differs(self.database.as_ref(), query.database.as_ref())
+            || differs(self.username.as_ref(), query.username.as_ref()))
+    }
+    pub fn into_owned(self) -> CredentialPattern<HasPasswordTrue> {
+        CredentialPattern {
+            hostname: self.hostname.map(Cow::into_owned),
+            port: self.port,
+            database: self.database.map(Cow::into_owned),
+            username: self.username.map(Cow::into_owned),
+            password: self.password.into_owned(),
+            _tag: PhantomData,
+        }
+    }
+}
+impl From<BorrowedCredentialPattern<'_>> for CredentialPattern<HasPasswordTrue> {
+    fn from(value: BorrowedCredentialPattern<'_>) -> Self {
+        value.into_owned()
+    }
+}
+
 /// A query for looking up credentials from [`PgPass`][super::PgPass]. `None` values
 /// indicate a wildcard.
 #[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -291,11 +392,50 @@ impl CredentialQuery {
             })
         }
     }
+    /// Returns true if the query has a value for the field (ie it is not a wildcard).
+    pub(crate) fn has(&self, field: Field) -> bool {
+        match field {
+            Field::Hostname => self.hostname.is_some(),
+            Field::Port => self.port.is_some(),
+            Field::Database => self.database.is_some(),
+            Field::Username => self.username.is_some(),
+        }
+    }
+}
+
+/// A field of a [`CredentialPattern`] or [`CredentialQuery`] which may hold a
+/// wildcard. (The password may not be a wildcard, and so is not included.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Hostname,
+    Port,
+    Database,
+    Username,
+}
+impl Field {
+    /// All fields, in the order they appear in a pgpass file.
+    pub const ALL: [Field; 4] = [
+        Field::Hostname,
+        Field::Port,
+        Field::Database,
+        Field::Username,
+    ];
+}
+impl Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Field::Hostname => "hostname",
+            Field::Port => "port",
+            Field::Database => "database",
+            Field::Username => "username",
+        })
+    }
 }
 
 /// An error encountered when using an invalid value to build a
 /// [`CredentialPattern`] or [`CredentialQuery`].
-#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InvalidField {
     #[error("Invalid hostname: {0}")]
     InvalidHostname(Invalidity),
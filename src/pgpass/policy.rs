@@ -0,0 +1,561 @@
+//! Policies restricting which credentials [`PgPass`][super::PgPass] will release.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use crate::Credentials;
+
+use super::FindError;
+
+/// Controls which matching patterns may be used to answer a query.
+///
+/// A pattern like `*:*:*:*:secret` will send it's password to any host a tool
+/// is pointed at. If the hostname comes from an untrusted source (such as a
+/// command line argument), use [`MatchPolicy::StrictHost`] to refuse these
+/// patterns. See [`PgPass::set_match_policy`][a] and
+/// [`QueryBuilder::strict_host`][b].
+///
+/// [a]: super::PgPass::set_match_policy
+/// [b]: super::QueryBuilder::strict_host
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum MatchPolicy {
+    /// Any matching pattern may be used. This is the behavior of `libpq`.
+    #[default]
+    Permissive,
+    /// Refuse to use a matching pattern if it's hostname is a wildcard, meaning
+    /// the hostname would be taken from the query. A
+    /// [`WildcardHostRefused`][FindError::WildcardHostRefused] error is returned
+    /// instead of credentials.
+    StrictHost,
+}
+impl MatchPolicy {
+    /// Check whether the pattern at `index`, which may have a wildcard
+    /// hostname, may be used to answer a query.
+    pub(crate) fn check(self, index: usize, wildcard_host: bool) -> Result<(), FindError> {
+        match self {
+            MatchPolicy::Permissive => Ok(()),
+            MatchPolicy::StrictHost if wildcard_host => {
+                Err(FindError::WildcardHostRefused { index })
+            }
+            MatchPolicy::StrictHost => Ok(()),
+        }
+    }
+}
+
+/// An allowlist of hosts which credentials may be released to. When attached to
+/// a [`PgPass`][super::PgPass] (see [`set_credential_policy`][a]), credentials
+/// for any other host are withheld, and a
+/// [`HostNotAllowed`][FindError::HostNotAllowed] error is returned instead.
+///
+/// This guards against a tool being tricked into sending internal passwords to
+/// an external host. Hostnames are compared as written; they are not resolved,
+/// so a network rule will only allow hosts written as IP addresses. An empty
+/// policy allows nothing.
+///
+/// ```
+/// # use postgres_secrets::pgpass::*;
+/// # fn main() -> anyhow::Result<()> {
+/// let policy = CredentialPolicy::default()
+///     .allow_domain("db.internal")
+///     .allow_private_networks();
+/// let pgpass: PgPass = "*:*:*:*:secret".parse()?;
+/// let pgpass = pgpass.with_credential_policy(policy);
+///
+/// let query = pgpass.query().database("my_database")?.username("username")?;
+/// assert!(query.clone().hostname("orders.db.internal")?.find().is_ok());
+/// assert!(query.clone().hostname("10.1.2.3")?.find().is_ok());
+/// assert!(query.clone().hostname("db.example.com")?.find().is_err());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [a]: super::PgPass::set_credential_policy
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(from = "RawCredentialPolicy")]
+pub struct CredentialPolicy {
+    hosts: Vec<String>,
+    domains: Vec<String>,
+    networks: Vec<IpNetwork>,
+    unix_sockets: bool,
+}
+/// A deserialized [`CredentialPolicy`], before it's hostnames are normalized.
+#[derive(Deserialize)]
+struct RawCredentialPolicy {
+    hosts: Vec<String>,
+    domains: Vec<String>,
+    networks: Vec<IpNetwork>,
+    unix_sockets: bool,
+}
+impl From<RawCredentialPolicy> for CredentialPolicy {
+    fn from(raw: RawCredentialPolicy) -> Self {
+        let policy = Self {
+            networks: raw.networks,
+            unix_sockets: raw.unix_sockets,
+            ..Default::default()
+        };
+        let policy = raw.hosts.into_iter().fold(policy, Self::allow_host);
+        raw.domains.into_iter().fold(policy, Self::allow_domain)
+    }
+}
+impl CredentialPolicy {
+    /// Allow exactly this hostname (ignoring case).
+    pub fn allow_host<T: ToString>(mut self, hostname: T) -> Self {
+        self.hosts.push(normalize_hostname(&hostname.to_string()));
+        self
+    }
+    /// Allow this domain and any of it's subdomains (ignoring case). For instance,
+    /// `example.com` allows `example.com` and `db.example.com`, but not
+    /// `badexample.com`.
+    pub fn allow_domain<T: ToString>(mut self, domain: T) -> Self {
+        let domain = domain.to_string();
+        self.domains
+            .push(normalize_hostname(domain.trim_start_matches('.')));
+        self
+    }
+    /// Allow any host written as an IP address within this network.
+    pub fn allow_network(mut self, network: IpNetwork) -> Self {
+        self.networks.push(network);
+        self
+    }
+    /// Allow the private, loopback, and link-local networks (eg `10.0.0.0/8`,
+    /// `127.0.0.0/8`, and `fc00::/7`).
+    pub fn allow_private_networks(self) -> Self {
+        [
+            IpNetwork::V4_PRIVATE_A,
+            IpNetwork::V4_PRIVATE_B,
+            IpNetwork::V4_PRIVATE_C,
+            IpNetwork::V4_LOOPBACK,
+            IpNetwork::V4_LINK_LOCAL,
+            IpNetwork::V6_UNIQUE_LOCAL,
+            IpNetwork::V6_LOOPBACK,
+            IpNetwork::V6_LINK_LOCAL,
+        ]
+        .into_iter()
+        .fold(self, Self::allow_network)
+    }
+    /// Allow Unix domain sockets (hostnames which are absolute paths, as in
+    /// `libpq`).
+    pub fn allow_unix_sockets(mut self) -> Self {
+        self.unix_sockets = true;
+        self
+    }
+    /// Returns true if credentials may be released to this host.
+    pub fn allows(&self, hostname: &str) -> bool {
+        if hostname.starts_with('/') {
+            return self.unix_sockets;
+        }
+        let hostname = normalize_hostname(hostname);
+        if let Some(addr) = parse_ip(&hostname) {
+            return self.networks.iter().any(|network| network.contains(addr));
+        }
+
+        self.hosts.contains(&hostname)
+            || self.domains.iter().any(|domain| {
+                hostname
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+            })
+    }
+    pub(crate) fn check(&self, credentials: &Credentials) -> Result<(), FindError> {
+        if self.allows(&credentials.hostname) {
+            Ok(())
+        } else {
+            Err(FindError::HostNotAllowed {
+                hostname: credentials.hostname.clone(),
+            })
+        }
+    }
+}
+
+fn normalize_hostname(hostname: &str) -> String {
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn parse_ip(hostname: &str) -> Option<IpAddr> {
+    let hostname = hostname
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(hostname);
+    hostname
+        .parse::<IpAddr>()
+        .ok()
+        .map(|addr| addr.to_canonical())
+}
+
+/// A range of IP addresses in CIDR notation, such as `10.0.0.0/8`. Used to
+/// build a [`CredentialPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "RawIpNetwork")]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+/// A deserialized [`IpNetwork`], before it's prefix is checked.
+#[derive(Deserialize)]
+struct RawIpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+impl TryFrom<RawIpNetwork> for IpNetwork {
+    type Error = InvalidNetwork;
+
+    fn try_from(raw: RawIpNetwork) -> Result<Self, Self::Error> {
+        Self::new(raw.addr, raw.prefix)
+    }
+}
+impl IpNetwork {
+    pub const V4_PRIVATE_A: Self = Self::v4(Ipv4Addr::new(10, 0, 0, 0), 8);
+    pub const V4_PRIVATE_B: Self = Self::v4(Ipv4Addr::new(172, 16, 0, 0), 12);
+    pub const V4_PRIVATE_C: Self = Self::v4(Ipv4Addr::new(192, 168, 0, 0), 16);
+    pub const V4_LOOPBACK: Self = Self::v4(Ipv4Addr::new(127, 0, 0, 0), 8);
+    pub const V4_LINK_LOCAL: Self = Self::v4(Ipv4Addr::new(169, 254, 0, 0), 16);
+    pub const V6_UNIQUE_LOCAL: Self = Self::v6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7);
+    pub const V6_LOOPBACK: Self = Self::v6(Ipv6Addr::LOCALHOST, 128);
+    pub const V6_LINK_LOCAL: Self = Self::v6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10);
+
+    const fn v4(addr: Ipv4Addr, prefix: u8) -> Self {
+        Self {
+            addr: IpAddr::V4(addr),
+            prefix,
+        }
+    }
+    const fn v6(addr: Ipv6Addr, prefix: u8) -> Self {
+        Self {
+            addr: IpAddr::V6(addr),
+            prefix,
+        }
+    }
+    /// Create a network from an address and a prefix length. Bits of the address
+    /// after the prefix are cleared, so `10.0.0.1/8` is the same network as
+    /// `10.0.0.0/8`.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, InvalidNetwork> {
+        let addr = match addr.to_canonical() {
+            IpAddr::V4(addr) if prefix <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            }
+            IpAddr::V6(addr) if prefix <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+            _ => return Err(InvalidNetwork::InvalidPrefix(prefix)),
+        };
+        Ok(Self { addr, prefix })
+    }
+    /// Returns true if the address is within the network.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                // An invalid prefix can't contain anything
+                let Some(shift) = 32u32.checked_sub(self.prefix as u32) else {
+                    return false;
+                };
+                let mask = u32::MAX.checked_shl(shift).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                // An invalid prefix can't contain anything
+                let Some(shift) = 128u32.checked_sub(self.prefix as u32) else {
+                    return false;
+                };
+                let mask = u128::MAX.checked_shl(shift).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl FromStr for IpNetwork {
+    type Err = InvalidNetwork;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (
+                addr,
+                Some(
+                    prefix
+                        .parse::<u8>()
+                        .map_err(|_| InvalidNetwork::InvalidPrefixSyntax)?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| InvalidNetwork::InvalidAddress)?;
+        let prefix = prefix.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        Self::new(addr, prefix)
+    }
+}
+impl Display for IpNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// An error encountered when parsing an [`IpNetwork`].
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InvalidNetwork {
+    #[error("Invalid network: could not parse the address.")]
+    InvalidAddress,
+    #[error("Invalid network: could not parse the prefix length.")]
+    InvalidPrefixSyntax,
+    #[error("Invalid network: {0} is too long for a prefix length.")]
+    InvalidPrefix(u8),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pgpass::{CredentialPattern, CredentialQuery, IncompleteCredential, PgPass};
+
+    fn pgpass() -> anyhow::Result<PgPass> {
+        Ok(PgPass::default()
+            .with(
+                CredentialPattern::default()
+                    .hostname("localhost")?
+                    .database("database")?
+                    .username("username")?
+                    .password("local")?,
+            )
+            .with(
+                CredentialPattern::default()
+                    .database("database")?
+                    .username("username")?
+                    .password("catch_all")?,
+            ))
+    }
+
+    #[test]
+    fn permissive_by_default() -> anyhow::Result<()> {
+        let pgpass = pgpass()?;
+
+        let actual = pgpass.query().hostname("example.com")?.find()?.unwrap();
+        assert_eq!(actual.password, "catch_all");
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_host_query() -> anyhow::Result<()> {
+        let pgpass = pgpass()?;
+
+        let actual = pgpass
+            .query()
+            .hostname("localhost")?
+            .strict_host()
+            .find()?
+            .unwrap();
+        assert_eq!(actual.password, "local");
+
+        let actual = pgpass.query().hostname("example.com")?.strict_host().find();
+        assert_eq!(actual, Err(FindError::WildcardHostRefused { index: 1 }));
+
+        let actual = pgpass.query().hostname("example.com")?.find()?.unwrap();
+        assert_eq!(actual.password, "catch_all");
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_host_pgpass() -> anyhow::Result<()> {
+        let pgpass = pgpass()?.with_match_policy(MatchPolicy::StrictHost);
+
+        let query = CredentialQuery::default().hostname("localhost")?;
+        assert_eq!(pgpass.find(&query)?.unwrap().password, "local");
+
+        let query = CredentialQuery::default().hostname("example.com")?;
+        assert_eq!(
+            pgpass.find(&query),
+            Err(FindError::WildcardHostRefused { index: 1 })
+        );
+        let actual = pgpass.query().hostname("example.com")?.find();
+        assert_eq!(actual, Err(FindError::WildcardHostRefused { index: 1 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn refused_before_missing_hostname() -> anyhow::Result<()> {
+        let pgpass = PgPass::default().with(CredentialPattern::default().password("secret")?);
+
+        let actual = pgpass.query().find();
+        assert_eq!(
+            actual,
+            Err(FindError::Incomplete(IncompleteCredential::MissingHostname))
+        );
+
+        let actual = pgpass.query().strict_host().find();
+        assert_eq!(actual, Err(FindError::WildcardHostRefused { index: 0 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn hosts_and_domains() {
+        let policy = CredentialPolicy::default()
+            .allow_host("db.example.com")
+            .allow_domain(".internal");
+
+        assert!(policy.allows("db.example.com"));
+        assert!(policy.allows("DB.Example.com."));
+        assert!(!policy.allows("www.db.example.com"));
+        assert!(policy.allows("internal"));
+        assert!(policy.allows("orders.db.internal"));
+        assert!(!policy.allows("notinternal"));
+        assert!(!policy.allows("internal.example.com"));
+        assert!(!policy.allows("/var/run/postgresql"));
+        assert!(!CredentialPolicy::default().allows("localhost"));
+    }
+
+    #[test]
+    fn networks() -> anyhow::Result<()> {
+        let policy = CredentialPolicy::default()
+            .allow_network("192.0.2.0/24".parse()?)
+            .allow_network("2001:db8::/32".parse()?);
+
+        assert!(policy.allows("192.0.2.1"));
+        assert!(!policy.allows("192.0.3.1"));
+        assert!(policy.allows("::ffff:192.0.2.1"));
+        assert!(policy.allows("2001:db8::1"));
+        assert!(policy.allows("[2001:db8::1]"));
+        assert!(!policy.allows("2001:db9::1"));
+        // Hostnames are not resolved
+        assert!(!policy.allows("localhost"));
+
+        let policy = CredentialPolicy::default().allow_private_networks();
+        assert!(policy.allows("10.0.0.1"));
+        assert!(policy.allows("172.31.255.255"));
+        assert!(!policy.allows("172.32.0.0"));
+        assert!(policy.allows("127.0.0.1"));
+        assert!(policy.allows("::1"));
+        assert!(policy.allows("fd00::1"));
+        assert!(!policy.allows("8.8.8.8"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn host_bits_are_cleared() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let hash = |network: &IpNetwork| {
+            let mut hasher = DefaultHasher::new();
+            network.hash(&mut hasher);
+            hasher.finish()
+        };
+        let a: IpNetwork = "10.0.0.1/8".parse().unwrap();
+        let b: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_eq!(a.to_string(), "10.0.0.0/8");
+        assert_eq!(
+            "fe80::1/10".parse::<IpNetwork>().unwrap(),
+            IpNetwork::V6_LINK_LOCAL
+        );
+        assert_eq!(
+            "1.2.3.4/0".parse::<IpNetwork>().unwrap().to_string(),
+            "0.0.0.0/0"
+        );
+    }
+
+    #[test]
+    fn parse_network() {
+        assert_eq!(
+            "10.0.0.0/8".parse(),
+            Ok(IpNetwork::new(Ipv4Addr::new(10, 0, 0, 0).into(), 8).unwrap())
+        );
+        assert_eq!(
+            "10.0.0.1".parse::<IpNetwork>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains(Ipv4Addr::BROADCAST.into()));
+        assert_eq!(
+            "10.0.0.0/33".parse::<IpNetwork>(),
+            Err(InvalidNetwork::InvalidPrefix(33))
+        );
+        assert_eq!(
+            "10.0.0.0/x".parse::<IpNetwork>(),
+            Err(InvalidNetwork::InvalidPrefixSyntax)
+        );
+        assert_eq!(
+            "example.com/8".parse::<IpNetwork>(),
+            Err(InvalidNetwork::InvalidAddress)
+        );
+    }
+
+    #[test]
+    fn find_checks_policy() -> anyhow::Result<()> {
+        let pgpass = PgPass::default()
+            .with(
+                CredentialPattern::default()
+                    .hostname("db.internal")?
+                    .database("database")?
+                    .username("username")?
+                    .password("internal")?,
+            )
+            .with(
+                CredentialPattern::default()
+                    .database("database")?
+                    .username("username")?
+                    .password("catch_all")?,
+            )
+            .with_credential_policy(CredentialPolicy::default().allow_host("db.internal"));
+
+        let actual = pgpass.query().hostname("db.internal")?.find()?.unwrap();
+        assert_eq!(actual.password, "internal");
+
+        let actual = pgpass.query().hostname("attacker.example.com")?.find();
+        assert_eq!(
+            actual,
+            Err(FindError::HostNotAllowed {
+                hostname: "attacker.example.com".to_string()
+            })
+        );
+
+        // No match is not a denial
+        let actual = pgpass
+            .query()
+            .hostname("attacker.example.com")?
+            .username("other")?
+            .find()?;
+        assert_eq!(actual, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn deserializing_validates() -> anyhow::Result<()> {
+        let network: IpNetwork = serde_json::from_str(r#"{"addr":"10.0.0.0","prefix":8}"#)?;
+        assert_eq!(network, IpNetwork::V4_PRIVATE_A);
+        assert!(serde_json::from_str::<IpNetwork>(r#"{"addr":"10.0.0.0","prefix":40}"#).is_err());
+        assert!(serde_json::from_str::<IpNetwork>(r#"{"addr":"::","prefix":129}"#).is_err());
+
+        let policy: CredentialPolicy = serde_json::from_str(
+            r#"{"hosts":["DB.Internal."],"domains":[".Example.COM"],"networks":[],"unix_sockets":false}"#,
+        )?;
+        assert_eq!(
+            policy,
+            CredentialPolicy::default()
+                .allow_host("db.internal")
+                .allow_domain("example.com")
+        );
+        assert!(policy.allows("db.internal"));
+        assert!(policy.allows("orders.example.com"));
+
+        // Serializing and deserializing doesn't change the policy
+        let policy = policy.allow_private_networks().allow_unix_sockets();
+        let json = serde_json::to_string(&policy)?;
+        assert_eq!(serde_json::from_str::<CredentialPolicy>(&json)?, policy);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+//! Reading pgpass files which are not valid UTF-8. See [`RawPgPass`].
+//!
+//! `libpq` treats each field as a sequence of bytes, so a file written in a
+//! legacy encoding (such as a Latin-1 password) works with `psql` but is rejected
+//! by [`PgPass::read`]. [`RawPgPass`] accepts any bytes, the way `libpq` does.
+
+use std::{
+    fmt::Debug, fs::File, io::Read, marker::PhantomData, num::NonZeroU16, path::Path, str,
+    string::FromUtf8Error,
+};
+
+use crate::{Credentials, DEFAULT_PORT};
+
+use super::{
+    parser, pattern::HasPasswordTrue, CredentialPattern, CredentialPolicy, CredentialQuery,
+    FindError, IncompleteCredential, LineNumbers, LoadError, LoadOptions, MatchPolicy,
+    ParsingError, PgPass, BOM,
+};
+
+/// A pgpass file whose fields are raw bytes rather than strings. It uses the same
+/// syntax as [`PgPass`], and the same [first match](super::MatchStrategy::FirstMatch)
+/// rule as `libpq`. The [`MatchPolicy`] and [`CredentialPolicy`] are enforced
+/// just as they are by [`PgPass::find`].
+///
+/// ```
+/// # use postgres_secrets::pgpass::*;
+/// # use postgres_secrets::pgpass::raw::RawPgPass;
+/// # fn main() -> anyhow::Result<()> {
+/// // A password encoded as Latin-1
+/// let file = b"localhost:*:*:*:caf\xe9";
+/// assert!(PgPass::read(&file[..]).is_err());
+///
+/// let pgpass = RawPgPass::read(&file[..])?;
+/// let query = CredentialQuery::default()
+///     .hostname("localhost")?
+///     .database("database")?
+///     .username("username")?;
+/// let creds = pgpass.find(&query)?.unwrap();
+/// assert_eq!(creds.password, b"caf\xe9");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RawPgPass {
+    patterns: Vec<RawCredentialPattern>,
+    lines: Vec<usize>,
+    match_policy: MatchPolicy,
+    credential_policy: Option<CredentialPolicy>,
+}
+impl RawPgPass {
+    /// Automatically locate and load the pgpass file. See [`PgPass::locate`].
+    pub fn load() -> Result<Self, LoadError> {
+        Self::load_with(&LoadOptions::default())
+    }
+    /// Automatically locate and load the pgpass file, enforcing the given limits.
+    pub fn load_with(options: &LoadOptions) -> Result<Self, LoadError> {
+        let Some(path) = PgPass::locate() else {
+            return Err(LoadError::CouldNotLocate);
+        };
+        Self::open_with(path, options)
+    }
+    /// Load credentials from the given file, with the default [`LoadOptions`].
+    pub fn read<F: Read>(f: F) -> Result<Self, LoadError> {
+        Self::read_with(f, &LoadOptions::default())
+    }
+    /// Load credentials from the given file, enforcing the given limits. A
+    /// leading UTF-8 byte order mark is ignored.
+    pub fn read_with<F: Read>(f: F, options: &LoadOptions) -> Result<Self, LoadError> {
+        let contents = options.read_limited(f)?;
+        options.check_line_lengths(&contents)?;
+        Self::from_contents(&contents, |entries| options.check_entries(entries))
+    }
+    /// Parse the contents of a file, calling `check` with the number of patterns
+    /// found so far before each one is added.
+    fn from_contents<E: From<ParsingError>>(
+        contents: &[u8],
+        mut check: impl FnMut(usize) -> Result<(), E>,
+    ) -> Result<Self, E> {
+        let mut pgpass = RawPgPass::default();
+        parser::for_each_pattern(&latin1_decode(contents), |pattern, line| {
+            check(pgpass.patterns.len() + 1)?;
+            pgpass.patterns.push(RawCredentialPattern {
+                hostname: pattern.hostname.as_deref().map(latin1_encode),
+                port: pattern.port,
+                database: pattern.database.as_deref().map(latin1_encode),
+                username: pattern.username.as_deref().map(latin1_encode),
+                password: latin1_encode(&pattern.password),
+            });
+            pgpass.lines.push(line);
+            Ok::<_, E>(())
+        })?;
+        Ok(pgpass)
+    }
+    /// Load credentials from the file at the given path, with the default
+    /// [`LoadOptions`].
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        Self::open_with(path, &LoadOptions::default())
+    }
+    /// Load credentials from the file at the given path, enforcing the given limits.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Self, LoadError> {
+        let f = File::open(path.as_ref())?;
+        Self::read_with(f, options)
+    }
+    /// Parse the contents of a pgpass file.
+    pub fn parse(contents: &[u8]) -> Result<Self, ParsingError> {
+        let contents = contents.strip_prefix(BOM).unwrap_or(contents);
+        Self::from_contents(contents, |_| Ok(()))
+    }
+    pub fn patterns(&self) -> &[RawCredentialPattern] {
+        &self.patterns
+    }
+    /// Set the [`MatchPolicy`] used by [`find`][RawPgPass::find]. See
+    /// [`PgPass::set_match_policy`].
+    pub fn set_match_policy(&mut self, policy: MatchPolicy) {
+        self.match_policy = policy;
+    }
+    /// Set the [`MatchPolicy`] used by [`find`][RawPgPass::find].
+    pub fn with_match_policy(mut self, policy: MatchPolicy) -> Self {
+        self.set_match_policy(policy);
+        self
+    }
+    /// Set the [`CredentialPolicy`] checked by [`find`][RawPgPass::find]. See
+    /// [`PgPass::set_credential_policy`]. A hostname which is not valid UTF-8 is
+    /// never allowed.
+    pub fn set_credential_policy(&mut self, policy: Option<CredentialPolicy>) {
+        self.credential_policy = policy;
+    }
+    /// Set the [`CredentialPolicy`] checked by [`find`][RawPgPass::find].
+    pub fn with_credential_policy(mut self, policy: CredentialPolicy) -> Self {
+        self.set_credential_policy(Some(policy));
+        self
+    }
+    /// Returns the first set of credentials matching the query, if one exists.
+    /// Fields are compared byte for byte. See [`PgPass::find`].
+    ///
+    /// As with [`PgPass::find`], an error is returned if the matching pattern is
+    /// refused by the [`MatchPolicy`], or the resulting host is not allowed by
+    /// the [`CredentialPolicy`].
+    pub fn find(&self, query: &CredentialQuery) -> Result<Option<RawCredentials>, FindError> {
+        let Some((index, pattern)) = self
+            .patterns
+            .iter()
+            .enumerate()
+            .find(|(_, pattern)| pattern.matches(query))
+        else {
+            return Ok(None);
+        };
+        self.match_policy.check(index, pattern.hostname.is_none())?;
+
+        fn fill(query: Option<&String>, pattern: Option<&Vec<u8>>) -> Option<Vec<u8>> {
+            query
+                .map(|query| query.as_bytes().to_vec())
+                .or_else(|| pattern.cloned())
+        }
+        let creds = RawCredentials {
+            hostname: fill(query.hostname.as_ref(), pattern.hostname.as_ref())
+                .ok_or(IncompleteCredential::MissingHostname)?,
+            port: query
+                .port
+                .or(pattern.port)
+                .unwrap_or(NonZeroU16::new(DEFAULT_PORT).unwrap()),
+            database: fill(query.database.as_ref(), pattern.database.as_ref())
+                .ok_or(IncompleteCredential::MissingDatabase)?,
+            username: fill(query.username.as_ref(), pattern.username.as_ref())
+                .ok_or(IncompleteCredential::MissingUsername)?,
+            password: pattern.password.clone(),
+        };
+        if let Some(policy) = self.credential_policy.as_ref() {
+            let allowed = str::from_utf8(&creds.hostname).is_ok_and(|h| policy.allows(h));
+            if !allowed {
+                return Err(FindError::HostNotAllowed {
+                    hostname: String::from_utf8_lossy(&creds.hostname).into_owned(),
+                });
+            }
+        }
+        Ok(Some(creds))
+    }
+}
+impl TryFrom<RawPgPass> for PgPass {
+    type Error = FromUtf8Error;
+
+    /// Convert every field to UTF-8, failing if any is invalid.
+    fn try_from(value: RawPgPass) -> Result<Self, Self::Error> {
+        let patterns = value
+            .patterns
+            .into_iter()
+            .map(CredentialPattern::try_from)
+            .collect::<Result<_, _>>()?;
+        let mut pgpass = PgPass {
+            patterns,
+            lines: LineNumbers(value.lines.into_iter().map(Some).collect()),
+            ..Default::default()
+        };
+        pgpass.set_match_policy(value.match_policy);
+        pgpass.set_credential_policy(value.credential_policy);
+        Ok(pgpass)
+    }
+}
+
+/// A row of a pgpass file, with fields as raw bytes. `None` represents a wildcard.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RawCredentialPattern {
+    pub hostname: Option<Vec<u8>>,
+    pub port: Option<NonZeroU16>,
+    pub database: Option<Vec<u8>>,
+    pub username: Option<Vec<u8>>,
+    pub password: Vec<u8>,
+}
+impl RawCredentialPattern {
+    /// Returns true if the pattern matches the query. See [`CredentialPattern::matches`].
+    pub fn matches(&self, query: &CredentialQuery) -> bool {
+        fn differs(pattern: Option<&Vec<u8>>, query: Option<&String>) -> bool {
+            pattern
+                .zip(query)
+                .is_some_and(|(pattern, query)| pattern != query.as_bytes())
+        }
+
+        !(differs(self.hostname.as_ref(), query.hostname.as_ref())
+            || self.port.zip(query.port).is_some_and(|(p, q)| p != q)
+            || differs(self.database.as_ref(), query.database.as_ref())
+            || differs(self.username.as_ref(), query.username.as_ref()))
+    }
+}
+impl TryFrom<RawCredentialPattern> for CredentialPattern<HasPasswordTrue> {
+    type Error = FromUtf8Error;
+
+    fn try_from(value: RawCredentialPattern) -> Result<Self, Self::Error> {
+        Ok(Self {
+            hostname: value.hostname.map(String::from_utf8).transpose()?,
+            port: value.port,
+            database: value.database.map(String::from_utf8).transpose()?,
+            username: value.username.map(String::from_utf8).transpose()?,
+            password: String::from_utf8(value.password)?,
+            _tag: PhantomData,
+        })
+    }
+}
+
+/// Credentials found by [`RawPgPass::find`], with fields as raw bytes.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RawCredentials {
+    pub hostname: Vec<u8>,
+    pub port: NonZeroU16,
+    pub database: Vec<u8>,
+    pub username: Vec<u8>,
+    pub password: Vec<u8>,
+}
+impl TryFrom<RawCredentials> for Credentials {
+    type Error = FromUtf8Error;
+
+    fn try_from(value: RawCredentials) -> Result<Self, Self::Error> {
+        Ok(Self {
+            hostname: String::from_utf8(value.hostname)?,
+            port: value.port,
+            database: String::from_utf8(value.database)?,
+            username: String::from_utf8(value.username)?,
+            password: String::from_utf8(value.password)?,
+        })
+    }
+}
+#[cfg(feature = "postgres")]
+impl TryFrom<RawCredentials> for postgres::Config {
+    type Error = str::Utf8Error;
+
+    /// The password is passed to Postgres as bytes, so it need not be UTF-8.
+    /// Every other field must be.
+    fn try_from(value: RawCredentials) -> Result<Self, Self::Error> {
+        let mut config = Self::new();
+        config
+            .host(str::from_utf8(&value.hostname)?)
+            .port(value.port.get())
+            .dbname(str::from_utf8(&value.database)?)
+            .user(str::from_utf8(&value.username)?)
+            .password(&value.password);
+        Ok(config)
+    }
+}
+impl Debug for RawCredentials {
+    // Hand-rolled to censor passwords
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawCredentials")
+            .field("hostname", &String::from_utf8_lossy(&self.hostname))
+            .field("port", &self.port)
+            .field("database", &String::from_utf8_lossy(&self.database))
+            .field("username", &String::from_utf8_lossy(&self.username))
+            .field("password", &"[ Censored ]")
+            .finish()
+    }
+}
+
+// The parser works on strings. Mapping each byte to the character with the same
+// value (as Latin-1 does) lets it parse arbitrary bytes: the syntax only uses
+// ASCII characters, which are unchanged, and the mapping can be exactly reversed.
+
+fn latin1_decode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| char::from(*b)).collect()
+}
+
+fn latin1_encode(s: &str) -> Vec<u8> {
+    s.chars()
+        .map(|c| u8::try_from(c).expect("only characters decoded from bytes are encoded"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn non_utf8_fields() -> anyhow::Result<()> {
+        let file = b"# Comment\nh\xf4te:*:*:*:one\n*:*:d\\:b\xff:*:tw\xc3\xa9\n";
+        let pgpass = RawPgPass::parse(file)?;
+        assert_eq!(
+            pgpass.patterns(),
+            &[
+                RawCredentialPattern {
+                    hostname: Some(b"h\xf4te".to_vec()),
+                    port: None,
+                    database: None,
+                    username: None,
+                    password: b"one".to_vec(),
+                },
+                RawCredentialPattern {
+                    hostname: None,
+                    port: None,
+                    database: Some(b"d:b\xff".to_vec()),
+                    username: None,
+                    password: "twé".as_bytes().to_vec(),
+                },
+            ]
+        );
+        assert!(PgPass::try_from(pgpass.clone()).is_err());
+
+        // "hôte" in UTF-8 doesn't match the Latin-1 bytes
+        let query = CredentialQuery::default()
+            .hostname("hôte")?
+            .database("database")?
+            .username("username")?;
+        assert_eq!(pgpass.find(&query)?, None);
+
+        let query = CredentialQuery::default()
+            .hostname("example.com")?
+            .username("username")?;
+        let creds = pgpass.find(&query)?.unwrap();
+        assert_eq!(creds.database, b"d:b\xff");
+        assert_eq!(creds.password, "twé".as_bytes());
+        assert!(Credentials::try_from(creds.clone()).is_err());
+        #[cfg(feature = "postgres")]
+        {
+            assert!(postgres::Config::try_from(creds).is_err());
+
+            let pgpass = RawPgPass::parse(b"*:*:*:*:caf\xe9")?;
+            let creds = pgpass.find(&query.database("database")?)?.unwrap();
+            let config = postgres::Config::try_from(creds)?;
+            assert_eq!(config.get_password(), Some(&b"caf\xe9"[..]));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn agrees_with_pgpass() -> anyhow::Result<()> {
+        let s = "\u{FEFF}one:2:th\\:ree:four:five\r\n\n*:*:b:c:d\\\\\n";
+        let raw = RawPgPass::parse(s.as_bytes())?;
+        let pgpass: PgPass = s.parse()?;
+        assert_eq!(PgPass::try_from(raw.clone())?, pgpass);
+        assert_eq!(raw.lines, vec![1, 3]);
+
+        assert_eq!(
+            RawPgPass::parse(b"a:b:c:d:e").unwrap_err(),
+            "a:b:c:d:e".parse::<PgPass>().unwrap_err()
+        );
+
+        // Only the byte order mark at the start of the file is ignored
+        let s = "\u{FEFF}\u{FEFF}host:*:*:*:password\n";
+        let raw = RawPgPass::parse(s.as_bytes())?;
+        assert_eq!(
+            raw.patterns[0].hostname.as_deref(),
+            Some(&b"\xef\xbb\xbfhost"[..])
+        );
+        assert_eq!(PgPass::try_from(raw)?, s.parse()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn policies_are_enforced() -> anyhow::Result<()> {
+        let pgpass = RawPgPass::parse(b"*:*:*:*:one\nh\xf4te:*:*:*:two\n")?;
+        let query = CredentialQuery::default()
+            .database("database")?
+            .username("username")?;
+
+        let strict = pgpass.clone().with_match_policy(MatchPolicy::StrictHost);
+        assert_eq!(
+            strict.find(&query.clone().hostname("localhost")?),
+            Err(FindError::WildcardHostRefused { index: 0 })
+        );
+
+        let allowlist = pgpass.with_credential_policy(CredentialPolicy::default().allow_host("db"));
+        assert!(allowlist.find(&query.clone().hostname("db")?)?.is_some());
+        assert_eq!(
+            allowlist.find(&query.clone().hostname("localhost")?),
+            Err(FindError::HostNotAllowed {
+                hostname: "localhost".to_string()
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn debug_is_censored() -> anyhow::Result<()> {
+        let pgpass = RawPgPass::parse(b"*:*:*:*:hunter\xb2")?;
+        let query = CredentialQuery::default()
+            .hostname("a")?
+            .database("b")?
+            .username("c")?;
+        let creds = pgpass.find(&query)?.unwrap();
+        assert!(!format!("{:?}", creds).contains("hunter"));
+
+        Ok(())
+    }
+}
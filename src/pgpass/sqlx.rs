@@ -0,0 +1,196 @@
+//! Looking up passwords for `sqlx`. Requires the `sqlx` feature.
+
+use std::env;
+
+use sqlx_postgres::PgConnectOptions;
+use thiserror::Error;
+
+use super::{pattern::InvalidField, CredentialQuery, FindError, PgPass};
+
+impl TryFrom<&PgConnectOptions> for CredentialQuery {
+    type Error = InvalidField;
+
+    /// Build a query from the host, port, database and user of the options. As in
+    /// `libpq`, a Unix socket is queried as `localhost`, and the database defaults
+    /// to the user.
+    fn try_from(value: &PgConnectOptions) -> Result<Self, Self::Error> {
+        let hostname = if value.get_socket().is_some() || value.get_host().starts_with('/') {
+            "localhost"
+        } else {
+            value.get_host()
+        };
+        CredentialQuery::default()
+            .hostname(hostname)?
+            .port(value.get_port())?
+            .database(value.get_database().unwrap_or(value.get_username()))?
+            .username(value.get_username())
+    }
+}
+
+/// An error encountered by [`PgPass::apply_to_sqlx`]. It is safe to log or
+/// display this error; it will not contain passwords.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SqlxError {
+    /// A field of the options can't be used in a query, such as an empty username.
+    #[error("{0}")]
+    InvalidField(#[from] InvalidField),
+    #[error("{0}")]
+    Find(#[from] FindError),
+}
+
+impl PgPass {
+    /// Set the password of `sqlx` connection options. This is the equivalent of
+    /// [`apply_to`][PgPass::apply_to] for `sqlx`.
+    ///
+    /// `sqlx` does not expose the password of the options, so any password the
+    /// caller already has is passed as `password`, and is used as it is. Only
+    /// if it is `None` is the password looked up with [`find`][PgPass::find].
+    /// As in `libpq`, a password from the `PGPASSWORD` environment variable
+    /// (which `sqlx` reads when the options are created) also takes precedence
+    /// over the pgpass file. If no pattern matches, the options are returned
+    /// unchanged. Build the options with
+    /// [`PgConnectOptions::new_without_pgpass`], so that `sqlx` does not read the
+    /// pgpass file itself.
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # use sqlx_postgres::PgConnectOptions;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let pgpass: PgPass = "db.example.com:*:orders:alice:secret".parse()?;
+    /// let options = PgConnectOptions::new_without_pgpass()
+    ///     .host("db.example.com")
+    ///     .database("orders")
+    ///     .username("alice");
+    /// let options = pgpass.apply_to_sqlx(options, None)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn apply_to_sqlx(
+        &self,
+        options: PgConnectOptions,
+        password: Option<&str>,
+    ) -> Result<PgConnectOptions, SqlxError> {
+        match self.sqlx_password(&options, password, |name| env::var(name).ok())? {
+            Some(password) => Ok(options.password(&password)),
+            None => Ok(options),
+        }
+    }
+    /// The password [`apply_to_sqlx`][PgPass::apply_to_sqlx] would set, if any.
+    fn sqlx_password(
+        &self,
+        options: &PgConnectOptions,
+        password: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<String>, SqlxError> {
+        if let Some(password) = password {
+            return Ok(Some(password.to_string()));
+        }
+        if env("PGPASSWORD").is_some() {
+            return Ok(None);
+        }
+        let query = CredentialQuery::try_from(options)?;
+        Ok(self.find(&query)?.map(|creds| creds.password))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Credentials;
+
+    #[test]
+    fn query_from_options() -> anyhow::Result<()> {
+        let options = PgConnectOptions::new_without_pgpass()
+            .host("example.com")
+            .port(123)
+            .database("database")
+            .username("username");
+        assert_eq!(
+            CredentialQuery::try_from(&options)?,
+            CredentialQuery::default()
+                .hostname("example.com")?
+                .port(123)?
+                .database("database")?
+                .username("username")?
+        );
+
+        let options = PgConnectOptions::new_without_pgpass()
+            .socket("/var/run/postgresql")
+            .username("alice");
+        let query = CredentialQuery::try_from(&options)?;
+        assert_eq!(query.hostname.as_deref(), Some("localhost"));
+        assert_eq!(query.database.as_deref(), Some("alice"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply() -> anyhow::Result<()> {
+        let pgpass: PgPass = "example.com:*:database:username:secret".parse()?;
+        let options = PgConnectOptions::new_without_pgpass()
+            .host("example.com")
+            .database("database")
+            .username("username");
+        let no_env = |_: &str| None;
+        assert_eq!(
+            pgpass.sqlx_password(&options, None, no_env)?.as_deref(),
+            Some("secret")
+        );
+        let with_password = pgpass.apply_to_sqlx(options.clone(), None)?;
+        assert_eq!(with_password.get_host(), "example.com");
+        assert_eq!(with_password.get_database(), Some("database"));
+        assert_eq!(with_password.get_username(), "username");
+
+        let other = options.clone().host("other.example.com");
+        assert_eq!(pgpass.sqlx_password(&other, None, no_env)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn existing_passwords_are_kept() -> anyhow::Result<()> {
+        let pgpass: PgPass = "*:*:*:*:secret".parse()?;
+        let options = PgConnectOptions::new_without_pgpass()
+            .host("example.com")
+            .username("username");
+        assert_eq!(
+            pgpass
+                .sqlx_password(&options, Some("explicit"), |_| None)?
+                .as_deref(),
+            Some("explicit")
+        );
+
+        let env = |name: &str| (name == "PGPASSWORD").then(|| "from-env".to_string());
+        assert_eq!(pgpass.sqlx_password(&options, None, env)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_fields_are_errors() {
+        let pgpass: PgPass = "*:*:*:*:secret".parse().unwrap();
+        let options = PgConnectOptions::new_without_pgpass()
+            .host("example.com")
+            .username("");
+        assert!(matches!(
+            pgpass.sqlx_password(&options, None, |_| None),
+            Err(SqlxError::InvalidField(_))
+        ));
+    }
+
+    #[test]
+    fn from_credentials() {
+        let creds = Credentials {
+            hostname: "example.com".to_string(),
+            port: 123.try_into().unwrap(),
+            database: "database".to_string(),
+            username: "username".to_string(),
+            password: "password".to_string(),
+        };
+        let options = PgConnectOptions::from(creds);
+        assert_eq!(options.get_host(), "example.com");
+        assert_eq!(options.get_port(), 123);
+        assert_eq!(options.get_database(), Some("database"));
+        assert_eq!(options.get_username(), "username");
+    }
+}
@@ -0,0 +1,383 @@
+//! Reading patterns one line at a time, without loading the whole file. See
+//! [`Entries`] and [`PgPass::find_in_reader`].
+
+use std::{
+    io::{BufRead, Read},
+    str,
+};
+
+use thiserror::Error;
+
+use crate::Credentials;
+
+use super::{
+    parser, pattern::HasPasswordTrue, CredentialPattern, CredentialQuery, FindError, LoadError,
+    LoadOptions, PgPass, BOM,
+};
+
+/// A pattern read from a pgpass file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Entry {
+    /// The line of the file the pattern was read from, starting from 1.
+    pub line: usize,
+    pub pattern: CredentialPattern<HasPasswordTrue>,
+}
+
+/// An iterator over the patterns of a pgpass file, reading from a [`BufRead`] one
+/// line at a time. Comments and blank lines are skipped.
+///
+/// An invalid line yields [`LoadError::InvalidLine`] (or [`LoadError::Utf8`]), and
+/// iteration may continue with the next line. After an I/O error, or after a
+/// [limit](LoadOptions) is exceeded, the iterator is exhausted.
+///
+/// ```
+/// # use postgres_secrets::pgpass::*;
+/// # use postgres_secrets::pgpass::stream::Entries;
+/// # fn main() -> anyhow::Result<()> {
+/// let file = "# Comment\nlocalhost:*:*:*:one\nlocalhost:bad:*:*:two\n";
+/// let mut entries = Entries::new(file.as_bytes());
+/// let entry = entries.next().unwrap()?;
+/// assert_eq!((entry.line, entry.pattern.password.as_str()), (2, "one"));
+/// assert!(matches!(
+///     entries.next(),
+///     Some(Err(LoadError::InvalidLine { line: 3, .. }))
+/// ));
+/// assert!(entries.next().is_none());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Entries<R> {
+    reader: R,
+    options: LoadOptions,
+    buffer: Vec<u8>,
+    line: usize,
+    bytes_read: u64,
+    entries: usize,
+    /// Patterns parsed from the current line which have not been returned yet.
+    /// A line only contains more than one pattern if it has a bare `\r`.
+    pending: Vec<CredentialPattern<HasPasswordTrue>>,
+    done: bool,
+}
+impl<R: BufRead> Entries<R> {
+    /// Read entries with the default [`LoadOptions`].
+    pub fn new(reader: R) -> Self {
+        Self::with_options(reader, LoadOptions::default())
+    }
+    /// Read entries, enforcing the given limits. No more than the maximum line
+    /// length is buffered, even if the line never ends.
+    pub fn with_options(reader: R, options: LoadOptions) -> Self {
+        Self {
+            reader,
+            options,
+            buffer: Vec::new(),
+            line: 0,
+            bytes_read: 0,
+            entries: 0,
+            pending: Vec::new(),
+            done: false,
+        }
+    }
+    /// Discard the iterator, returning the reader. It is positioned after the
+    /// last line which was read.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+    fn next_line(&mut self) -> Result<bool, LoadError> {
+        self.buffer.clear();
+        // Read one byte past each limit, so that we can tell it was exceeded.
+        // Lines may also have a "\r\n" ending, which doesn't count.
+        let line_budget = self
+            .options
+            .max_line_length()
+            .map_or(u64::MAX, |limit| (limit as u64).saturating_add(3));
+        let file_budget = self.options.max_file_size().map_or(u64::MAX, |limit| {
+            limit.saturating_sub(self.bytes_read).saturating_add(1)
+        });
+        let n = (&mut self.reader)
+            .take(line_budget.min(file_budget))
+            .read_until(b'\n', &mut self.buffer)?;
+        if n == 0 {
+            return Ok(false);
+        }
+        self.line += 1;
+        self.bytes_read += n as u64;
+
+        if let Some(limit) = self.options.max_file_size() {
+            if self.bytes_read > limit {
+                return Err(LoadError::FileTooLarge { limit });
+            }
+        }
+        if let Some(limit) = self.options.max_line_length() {
+            let content = self
+                .buffer
+                .strip_suffix(b"\n")
+                .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                .unwrap_or(&self.buffer);
+            if content.len() > limit {
+                return Err(LoadError::LineTooLong {
+                    line: self.line,
+                    limit,
+                });
+            }
+        }
+
+        let mut contents = &self.buffer[..];
+        if self.line == 1 {
+            contents = contents.strip_prefix(BOM).unwrap_or(contents);
+        }
+        let s = str::from_utf8(contents).map_err(|source| LoadError::Utf8 {
+            line: self.line,
+            source,
+        })?;
+        let mut patterns = Vec::new();
+        parser::for_each_pattern(s, |pattern, _| {
+            patterns.push(pattern.into_owned());
+            Ok(())
+        })
+        .map_err(|source| LoadError::InvalidLine {
+            line: self.line,
+            source,
+        })?;
+
+        self.entries += patterns.len();
+        self.options.check_entries(self.entries)?;
+        // Reversed, so that they can be popped in order
+        patterns.reverse();
+        self.pending = patterns;
+        Ok(true)
+    }
+}
+impl<R: BufRead> Iterator for Entries<R> {
+    type Item = Result<Entry, LoadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pattern) = self.pending.pop() {
+                return Some(Ok(Entry {
+                    line: self.line,
+                    pattern,
+                }));
+            }
+            if self.done {
+                return None;
+            }
+            match self.next_line() {
+                Ok(true) => (),
+                Ok(false) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(e @ (LoadError::InvalidLine { .. } | LoadError::Utf8 { .. })) => {
+                    return Some(Err(e))
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// An error encountered by [`PgPass::find_in_reader`].
+#[derive(Error, Debug)]
+pub enum LookupError {
+    #[error("{0}")]
+    Load(#[from] LoadError),
+    #[error("{0}")]
+    Find(#[from] FindError),
+}
+
+impl PgPass {
+    /// Look up credentials in a pgpass file without loading all of it, stopping
+    /// at the first matching pattern. This behaves like [`find`][PgPass::find]
+    /// with the default [`MatchStrategy`][super::MatchStrategy] and policies.
+    ///
+    /// Lines after the matching pattern are never read, so an invalid line is
+    /// only reported if it comes before the match. The default [`LoadOptions`]
+    /// are used; see [`find_in_reader_with`][PgPass::find_in_reader_with].
+    ///
+    /// ```
+    /// # use postgres_secrets::pgpass::*;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let file = "example.com:*:*:*:one\n*:*:*:*:two\n";
+    /// let query = CredentialQuery::default()
+    ///     .hostname("localhost")?
+    ///     .database("database")?
+    ///     .username("username")?;
+    /// let creds = PgPass::find_in_reader(file.as_bytes(), &query)?;
+    /// assert_eq!(creds.unwrap().password, "two");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn find_in_reader<R: BufRead>(
+        reader: R,
+        query: &CredentialQuery,
+    ) -> Result<Option<Credentials>, LookupError> {
+        Self::find_in_reader_with(reader, query, LoadOptions::default())
+    }
+    /// [`find_in_reader`][PgPass::find_in_reader], enforcing the given limits.
+    pub fn find_in_reader_with<R: BufRead>(
+        reader: R,
+        query: &CredentialQuery,
+        options: LoadOptions,
+    ) -> Result<Option<Credentials>, LookupError> {
+        for entry in Entries::with_options(reader, options) {
+            let entry = entry?;
+            if entry.pattern.matches(query) {
+                let creds =
+                    Self::pattern_to_creds(query, &entry.pattern).map_err(FindError::from)?;
+                return Ok(Some(creds));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pgpass::IncompleteCredential;
+    use std::io::{self, BufReader, Read};
+
+    #[test]
+    fn entries() -> anyhow::Result<()> {
+        let s = "# Comment\r\none:2:three:four:five\r\n\n*:*:b:c:d\\\\";
+        let entries: Vec<_> = Entries::new(s.as_bytes()).collect::<Result<_, _>>()?;
+        let pgpass: PgPass = s.parse()?;
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line, 2);
+        assert_eq!(entries[1].line, 4);
+        assert_eq!(entries[1].pattern.password, "d\\");
+        for (index, entry) in entries.iter().enumerate() {
+            assert_eq!(Some(entry.line), pgpass.line(index));
+            assert_eq!(entry.pattern, pgpass.patterns[index]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let s: &[u8] = b"a:*:*:*:one\nb:*:*:*:\xff\nc:*:*:*:three\n";
+        let results: Vec<_> = Entries::new(s).collect();
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(LoadError::Utf8 { line: 2, .. })));
+        assert!(results[2].is_ok());
+    }
+
+    /// A reader which fails after returning its contents.
+    struct Failing<'a>(&'a [u8]);
+    impl Read for Failing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::other("broken pipe"));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn io_errors_end_iteration() {
+        let reader = BufReader::new(Failing(b"a:*:*:*:one\n"));
+        let results: Vec<_> = Entries::new(reader).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(LoadError::Io(_))));
+    }
+
+    #[test]
+    fn find_stops_at_first_match() -> anyhow::Result<()> {
+        // The reader fails after the matching line, but it is never reached
+        let reader = BufReader::with_capacity(16, Failing(b"a:*:*:*:one\nb:*:*:*:two\n"));
+        let query = CredentialQuery::default()
+            .hostname("a")?
+            .database("database")?
+            .username("username")?;
+        assert_eq!(
+            PgPass::find_in_reader(reader, &query)?.unwrap().password,
+            "one"
+        );
+
+        let query = CredentialQuery::default().hostname("a")?;
+        assert!(matches!(
+            PgPass::find_in_reader("a:*:*:*:one".as_bytes(), &query),
+            Err(LookupError::Find(FindError::Incomplete(
+                IncompleteCredential::MissingDatabase
+            )))
+        ));
+
+        let query = CredentialQuery::default().hostname("c")?;
+        assert!(PgPass::find_in_reader("a:*:*:*:one".as_bytes(), &query)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn limits() {
+        let s = "a:*:*:*:one\r\nbb:*:*:*:two\n# Comment\nc:*:*:*:three";
+        let collect = |options: LoadOptions| {
+            Entries::with_options(s.as_bytes(), options)
+                .map(|entry| entry.map(|entry| entry.line))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(collect(LoadOptions::unlimited())
+            .into_iter()
+            .all(|line| line.is_ok()));
+
+        let lines = collect(LoadOptions::unlimited().with_max_line_length(Some(11)));
+        assert_eq!(lines.len(), 2);
+        assert!(matches!(lines[0], Ok(1)));
+        assert!(matches!(
+            lines[1],
+            Err(LoadError::LineTooLong { line: 2, limit: 11 })
+        ));
+
+        let lines = collect(LoadOptions::unlimited().with_max_file_size(Some(20)));
+        assert_eq!(lines.len(), 2);
+        assert!(matches!(
+            lines[1],
+            Err(LoadError::FileTooLarge { limit: 20 })
+        ));
+
+        let lines = collect(LoadOptions::unlimited().with_max_entries(Some(2)));
+        assert_eq!(lines.len(), 3);
+        assert!(matches!(lines[1], Ok(2)));
+        assert!(matches!(
+            lines[2],
+            Err(LoadError::TooManyEntries { limit: 2 })
+        ));
+    }
+
+    #[test]
+    fn endless_line() {
+        let options = LoadOptions::default().with_max_line_length(Some(1024));
+        let mut entries = Entries::with_options(BufReader::new(io::repeat(b'a')), options);
+        assert!(matches!(
+            entries.next(),
+            Some(Err(LoadError::LineTooLong {
+                line: 1,
+                limit: 1024
+            }))
+        ));
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn invalid_lines_before_match() -> anyhow::Result<()> {
+        let s = "a:*:*:*:one\na:x:*:*:two\nb:*:*:*:three";
+        let query = CredentialQuery::default()
+            .hostname("b")?
+            .database("database")?
+            .username("username")?;
+        assert!(matches!(
+            PgPass::find_in_reader(s.as_bytes(), &query),
+            Err(LookupError::Load(LoadError::InvalidLine { line: 2, .. }))
+        ));
+
+        Ok(())
+    }
+}
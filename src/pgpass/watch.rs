@@ -0,0 +1,416 @@
+//! Reloading a pgpass file when it changes, without restarting the process. See
+//! [`WatchedPgPass`].
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Condvar, Mutex, RwLock},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, warn};
+
+use super::{LoadError, LoadOptions, PgPass};
+
+/// A [`PgPass`] which is reloaded when it's file changes, such as when a secret
+/// is rotated.
+///
+/// A background thread polls the file's metadata (modification time, size, and
+/// on Unix the inode), and reparses it when any of them change. The new
+/// [`PgPass`] is swapped in atomically; readers holding an older
+/// [`snapshot`][WatchedPgPass::snapshot] are unaffected. If a reload fails (for
+/// instance, because the file is invalid or can't be read), the last good
+/// snapshot is kept and the error is reported to subscribers.
+///
+/// The settings of the current snapshot, such as it's
+/// [`MatchStrategy`][super::MatchStrategy], policies and field key, are carried
+/// over to each reloaded snapshot. Use [`configure`][WatchedPgPass::configure]
+/// to change them.
+///
+/// The thread is stopped when the `WatchedPgPass` is dropped.
+///
+/// ```no_run
+/// # use postgres_secrets::pgpass::*;
+/// # use postgres_secrets::pgpass::watch::{WatchedPgPass, WatchEvent};
+/// # fn main() -> anyhow::Result<()> {
+/// let watched = WatchedPgPass::open("/run/secrets/pgpass")?;
+/// let events = watched.subscribe();
+/// let creds = watched
+///     .snapshot()
+///     .query()
+///     .hostname("example.com")?
+///     .find()?;
+/// // Later...
+/// if let Ok(WatchEvent::Failed(e)) = events.try_recv() {
+///     eprintln!("Failed to reload the pgpass file: {}", e);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct WatchedPgPass {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// A change to a [`WatchedPgPass`]. See [`WatchedPgPass::subscribe`].
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    /// The file was reloaded, and this is the new snapshot.
+    Reloaded(Arc<PgPass>),
+    /// The file changed, but could not be reloaded. The previous snapshot is
+    /// still in use.
+    Failed(Arc<LoadError>),
+}
+
+#[derive(Debug)]
+struct Shared {
+    path: PathBuf,
+    options: LoadOptions,
+    snapshot: RwLock<Arc<PgPass>>,
+    state: Mutex<State>,
+    /// Signalled when the watcher should stop.
+    stop: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    fingerprint: Option<Fingerprint>,
+    /// The number of reloads started, used to order their results.
+    reloads_started: u64,
+    /// The most recently started reload whose result has been applied.
+    reload_applied: u64,
+    last_error: Option<Arc<LoadError>>,
+    subscribers: Vec<mpsc::Sender<WatchEvent>>,
+    stopped: bool,
+}
+
+/// The metadata compared to decide whether a file has changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fingerprint {
+    modified: Option<SystemTime>,
+    len: u64,
+    #[cfg(unix)]
+    inode: u64,
+}
+impl Fingerprint {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(&metadata),
+        })
+    }
+}
+
+impl WatchedPgPass {
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// Load the file at the given path, and watch it for changes. The default
+    /// [`LoadOptions`] and [interval][WatchedPgPass::DEFAULT_INTERVAL] are used.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        Self::open_with(path, &LoadOptions::default(), Self::DEFAULT_INTERVAL)
+    }
+    /// Load the file at the given path, and check it for changes every `interval`.
+    /// Fails if the file can't be loaded initially.
+    pub fn open_with<P: AsRef<Path>>(
+        path: P,
+        options: &LoadOptions,
+        interval: Duration,
+    ) -> Result<Self, LoadError> {
+        let path = path.as_ref().to_path_buf();
+        let fingerprint = Fingerprint::of(&path);
+        let pgpass = PgPass::open_with(&path, options)?;
+
+        let shared = Arc::new(Shared {
+            path,
+            options: *options,
+            snapshot: RwLock::new(Arc::new(pgpass)),
+            state: Mutex::new(State {
+                fingerprint,
+                ..Default::default()
+            }),
+            stop: Condvar::new(),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("pgpass-watcher".to_string())
+                .spawn(move || shared.watch(interval))
+                .map_err(LoadError::Io)?
+        };
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+    /// The current [`PgPass`]. It is not affected by later reloads.
+    pub fn snapshot(&self) -> Arc<PgPass> {
+        self.shared.snapshot()
+    }
+    /// Receive a [`WatchEvent`] whenever the file changes.
+    pub fn subscribe(&self) -> mpsc::Receiver<WatchEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared.lock().subscribers.push(sender);
+        receiver
+    }
+    /// The error from the most recent reload, or `None` if it succeeded.
+    pub fn last_error(&self) -> Option<Arc<LoadError>> {
+        self.shared.lock().last_error.clone()
+    }
+    /// Reload the file immediately, whether or not it has changed. Subscribers
+    /// are notified as they would be for a change.
+    pub fn reload_now(&self) -> Result<Arc<PgPass>, Arc<LoadError>> {
+        let fingerprint = Fingerprint::of(&self.shared.path);
+        self.shared.lock().fingerprint = fingerprint;
+        self.shared.reload()
+    }
+    /// Modify the current snapshot, for instance to set a
+    /// [`MatchPolicy`][super::MatchPolicy]. Settings are kept when the file is
+    /// reloaded, but patterns added here are not.
+    pub fn configure(&self, f: impl FnOnce(&mut PgPass)) {
+        // Hold the state lock, so that we don't race with a reload
+        let _state = self.shared.lock();
+        let mut snapshot = self
+            .shared
+            .snapshot
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut pgpass = PgPass::clone(&snapshot);
+        f(&mut pgpass);
+        *snapshot = Arc::new(pgpass);
+    }
+    pub fn path(&self) -> &Path {
+        &self.shared.path
+    }
+}
+impl Drop for WatchedPgPass {
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.stop.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // The state remains consistent even if a subscriber panicked
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn snapshot(&self) -> Arc<PgPass> {
+        self.snapshot
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+    fn watch(&self, interval: Duration) {
+        loop {
+            let state = self
+                .stop
+                .wait_timeout_while(self.lock(), interval, |state| !state.stopped)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+            if state.stopped {
+                return;
+            }
+            // The file system is only touched without the lock held
+            drop(state);
+
+            let fingerprint = Fingerprint::of(&self.path);
+            let mut state = self.lock();
+            if fingerprint != state.fingerprint {
+                debug!("pgpass file {:?} changed; reloading", self.path);
+                state.fingerprint = fingerprint;
+                drop(state);
+                // Errors are reported to subscribers, and retried on the next change
+                let _ = self.reload();
+            }
+        }
+    }
+    /// Load the file without holding the lock, then swap in the result. The
+    /// result is discarded if a reload which started later was already applied.
+    fn reload(&self) -> Result<Arc<PgPass>, Arc<LoadError>> {
+        let generation = {
+            let mut state = self.lock();
+            state.reloads_started += 1;
+            state.reloads_started
+        };
+        let loaded = PgPass::open_with(&self.path, &self.options);
+
+        let mut state = self.lock();
+        if generation < state.reload_applied {
+            debug!("Discarding a stale reload of pgpass file {:?}", self.path);
+            return match loaded {
+                Ok(_) => Ok(self.snapshot()),
+                Err(e) => Err(Arc::new(e)),
+            };
+        }
+        state.reload_applied = generation;
+        let (result, event) = match loaded {
+            Ok(mut pgpass) => {
+                let mut snapshot = self.snapshot.write().unwrap_or_else(|e| e.into_inner());
+                pgpass.settings = snapshot.settings.clone();
+                let pgpass = Arc::new(pgpass);
+                *snapshot = pgpass.clone();
+                state.last_error = None;
+                (Ok(pgpass.clone()), WatchEvent::Reloaded(pgpass))
+            }
+            Err(e) => {
+                warn!("Failed to reload pgpass file {:?}: {}", self.path, e);
+                let e = Arc::new(e);
+                state.last_error = Some(e.clone());
+                (Err(e.clone()), WatchEvent::Failed(e))
+            }
+        };
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        pgpass::{CredentialQuery, MatchPolicy},
+        test_utils::TempFile,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn password(pgpass: &PgPass) -> String {
+        let query = CredentialQuery::default()
+            .hostname("localhost")
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username("username")
+            .unwrap();
+        pgpass.find(&query).unwrap().unwrap().password
+    }
+
+    #[test]
+    fn reloads_on_change() -> anyhow::Result<()> {
+        let file = TempFile::new("watch-change", "localhost:*:*:*:one\n");
+        let watched =
+            WatchedPgPass::open_with(&file.0, &LoadOptions::default(), Duration::from_millis(10))?;
+        let events = watched.subscribe();
+        let old = watched.snapshot();
+        assert_eq!(password(&old), "one");
+
+        file.replace("localhost:*:*:*:second\n");
+        match events.recv_timeout(TIMEOUT)? {
+            WatchEvent::Reloaded(pgpass) => assert_eq!(password(&pgpass), "second"),
+            WatchEvent::Failed(e) => panic!("reload failed: {}", e),
+        }
+        assert_eq!(password(&watched.snapshot()), "second");
+        // Old snapshots are unaffected
+        assert_eq!(password(&old), "one");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_last_good_snapshot() -> anyhow::Result<()> {
+        let file = TempFile::new("watch-invalid", "localhost:*:*:*:one\n");
+        let watched =
+            WatchedPgPass::open_with(&file.0, &LoadOptions::default(), Duration::from_millis(10))?;
+        let events = watched.subscribe();
+
+        file.replace("localhost:not_a_port:*:*:two\n");
+        match events.recv_timeout(TIMEOUT)? {
+            WatchEvent::Failed(e) => assert!(matches!(*e, LoadError::SyntaxError(_))),
+            WatchEvent::Reloaded(_) => panic!("invalid file was loaded"),
+        }
+        assert_eq!(password(&watched.snapshot()), "one");
+        assert!(watched.last_error().is_some());
+
+        fs::remove_file(&file.0)?;
+        match events.recv_timeout(TIMEOUT)? {
+            WatchEvent::Failed(e) => assert!(matches!(*e, LoadError::Io(_))),
+            WatchEvent::Reloaded(_) => panic!("missing file was loaded"),
+        }
+        assert_eq!(password(&watched.snapshot()), "one");
+
+        file.replace("localhost:*:*:*:three\n");
+        assert!(matches!(
+            events.recv_timeout(TIMEOUT)?,
+            WatchEvent::Reloaded(_)
+        ));
+        assert_eq!(password(&watched.snapshot()), "three");
+        assert!(watched.last_error().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reload_now_keeps_settings() -> anyhow::Result<()> {
+        let file = TempFile::new("watch-now", "localhost:*:*:*:one\n");
+        // Long enough that the watcher never polls during the test
+        let watched =
+            WatchedPgPass::open_with(&file.0, &LoadOptions::default(), Duration::from_secs(3600))?;
+        watched.configure(|pgpass| pgpass.set_match_policy(MatchPolicy::StrictHost));
+
+        file.replace("localhost:*:*:*:two\n");
+        let pgpass = watched.reload_now().map_err(|e| anyhow::anyhow!("{}", e))?;
+        assert_eq!(password(&pgpass), "two");
+        assert_eq!(pgpass.settings.match_policy, MatchPolicy::StrictHost);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "field-encryption")]
+    #[test]
+    fn reload_keeps_field_key() -> anyhow::Result<()> {
+        use crate::pgpass::encrypted_field::FieldKey;
+
+        let key = FieldKey::generate();
+        let line = |password: &str| {
+            format!(
+                "localhost:*:*:*:{}\n",
+                key.encrypt(password).replace(':', "\\:")
+            )
+        };
+        let file = TempFile::new("watch-key", &line("one"));
+        let watched =
+            WatchedPgPass::open_with(&file.0, &LoadOptions::default(), Duration::from_secs(3600))?;
+        let events = watched.subscribe();
+        watched.configure(|pgpass| pgpass.set_field_key(Some(key.clone())));
+        assert_eq!(password(&watched.snapshot()), "one");
+
+        file.replace(&line("two"));
+        let pgpass = watched.reload_now().map_err(|e| anyhow::anyhow!("{}", e))?;
+        assert_eq!(password(&pgpass), "two");
+        assert!(pgpass.field_key().is_some());
+        match events.recv_timeout(TIMEOUT)? {
+            WatchEvent::Reloaded(pgpass) => assert_eq!(password(&pgpass), "two"),
+            WatchEvent::Failed(e) => panic!("reload failed: {}", e),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_reloads() -> anyhow::Result<()> {
+        let file = TempFile::new("watch-concurrent", "localhost:*:*:*:one\n");
+        let watched =
+            WatchedPgPass::open_with(&file.0, &LoadOptions::default(), Duration::from_secs(3600))?;
+
+        file.replace("localhost:*:*:*:two\n");
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| watched.reload_now().unwrap());
+            }
+        });
+        assert_eq!(password(&watched.snapshot()), "two");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! A `bb8` connection manager for `tokio_postgres`. Requires the `bb8` feature.
+
+use ::bb8::ManageConnection;
+use tokio_postgres::{
+    tls::{MakeTlsConnect, TlsConnect},
+    Client, Socket,
+};
+
+use super::{connect_tokio, connect_with_retry, find, ConnectError, PgPassSource};
+use crate::pgpass::CredentialQuery;
+
+/// A pool of `tokio_postgres` clients, which looks up the password every time it
+/// connects.
+pub type Pool<T> = ::bb8::Pool<PgPassConnectionManager<T>>;
+
+/// A [`ManageConnection`] which connects with the [`Credentials`][crate::Credentials]
+/// a query finds in a [`PgPassSource`]. See the [module documentation](super).
+///
+/// The connection of each client is driven by a task spawned onto the current
+/// `tokio` runtime.
+///
+/// ```no_run
+/// # use postgres_secrets::{pgpass::{watch::WatchedPgPass, CredentialQuery}, pool::bb8::*};
+/// # async fn example() -> anyhow::Result<()> {
+/// let query = CredentialQuery::default()
+///     .hostname("db.example.com")?
+///     .username("alice")?;
+/// let watched = WatchedPgPass::open("/run/secrets/pgpass")?;
+/// let manager = PgPassConnectionManager::new(query, watched, tokio_postgres::NoTls);
+/// let pool = Pool::builder().max_size(16).build(manager).await?;
+///
+/// let client = pool.get().await?;
+/// client.simple_query("SELECT 1").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PgPassConnectionManager<T> {
+    query: CredentialQuery,
+    source: PgPassSource,
+    tls: T,
+}
+impl<T> PgPassConnectionManager<T> {
+    pub fn new(query: CredentialQuery, source: impl Into<PgPassSource>, tls: T) -> Self {
+        Self {
+            query,
+            source: source.into(),
+            tls,
+        }
+    }
+    pub fn query(&self) -> &CredentialQuery {
+        &self.query
+    }
+    pub fn source(&self) -> &PgPassSource {
+        &self.source
+    }
+}
+
+impl<T> ManageConnection for PgPassConnectionManager<T>
+where
+    T: MakeTlsConnect<Socket> + Clone + Sync + Send + 'static,
+    T::Stream: Sync + Send,
+    T::TlsConnect: Sync + Send,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    type Connection = Client;
+    type Error = ConnectError;
+
+    async fn connect(&self) -> Result<Client, ConnectError> {
+        connect_with_retry(
+            &self.source,
+            |pgpass| find::<tokio_postgres::Config>(pgpass, &self.query),
+            |config| connect_tokio(config, self.tls.clone()),
+        )
+        .await
+    }
+
+    async fn is_valid(&self, client: &mut Client) -> Result<(), ConnectError> {
+        client.simple_query("").await?;
+        Ok(())
+    }
+
+    fn has_broken(&self, client: &mut Client) -> bool {
+        client.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        pgpass::{watch::WatchedPgPass, LoadOptions, PgPass},
+        pool::fake_server::FakeServer,
+        test_utils::TempFile,
+    };
+
+    fn query(server: &FakeServer) -> CredentialQuery {
+        CredentialQuery::default()
+            .hostname("127.0.0.1")
+            .unwrap()
+            .port(server.port())
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username("username")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn connects_with_pgpass_password() -> anyhow::Result<()> {
+        let server = FakeServer::start("secret");
+        let pgpass: PgPass = "127.0.0.1:*:database:username:secret".parse()?;
+        let manager = PgPassConnectionManager::new(query(&server), pgpass, tokio_postgres::NoTls);
+        let pool = Pool::builder().max_size(1).build(manager).await?;
+
+        pool.get().await?.simple_query("").await?;
+        assert_eq!(server.attempts(), ["secret"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reloads_after_rotation() -> anyhow::Result<()> {
+        let server = FakeServer::start("old");
+        let file = TempFile::new("bb8-rotation", "127.0.0.1:*:database:username:old\n");
+        let watched =
+            WatchedPgPass::open_with(&file.0, &LoadOptions::default(), Duration::from_secs(3600))?;
+        let manager = PgPassConnectionManager::new(query(&server), watched, tokio_postgres::NoTls);
+
+        manager.connect().await?;
+
+        file.replace("127.0.0.1:*:database:username:new\n");
+        server.set_password("new");
+        manager.connect().await?;
+        assert_eq!(server.attempts(), ["old", "old", "new"]);
+
+        Ok(())
+    }
+}
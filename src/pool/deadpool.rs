@@ -0,0 +1,203 @@
+//! A `deadpool` manager for `tokio_postgres`. Requires the
+//! `deadpool` feature.
+
+use ::deadpool::managed::{self, Metrics, RecycleError, RecycleResult};
+use tokio_postgres::{
+    tls::{MakeTlsConnect, TlsConnect},
+    Client, Socket,
+};
+
+use super::{apply, connect_tokio, connect_with_retry, ConnectError, PgPassSource};
+
+/// A pool of `tokio_postgres` clients, which looks up the password every time it
+/// connects.
+pub type Pool<T> = managed::Pool<PgPassManager<T>>;
+
+/// A [`Manager`](managed::Manager) which sets the password of each new
+/// connection from a [`PgPassSource`]. See the [module documentation](super).
+///
+/// The connection of each client is driven by a task spawned onto the current
+/// `tokio` runtime.
+///
+/// ```no_run
+/// # use postgres_secrets::{pgpass::watch::WatchedPgPass, pool::deadpool::*};
+/// # async fn example() -> anyhow::Result<()> {
+/// let config: tokio_postgres::Config = "host=db.example.com user=alice".parse()?;
+/// let watched = WatchedPgPass::open("/run/secrets/pgpass")?;
+/// let manager = PgPassManager::new(config, watched, tokio_postgres::NoTls);
+/// let pool = Pool::builder(manager).max_size(16).build()?;
+///
+/// let client = pool.get().await?;
+/// client.simple_query("SELECT 1").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PgPassManager<T> {
+    config: tokio_postgres::Config,
+    source: PgPassSource,
+    tls: T,
+}
+impl<T> PgPassManager<T> {
+    /// Create a manager which connects with `config`. `config` should not have a
+    /// password, otherwise the pgpass file is never consulted.
+    pub fn new(config: tokio_postgres::Config, source: impl Into<PgPassSource>, tls: T) -> Self {
+        Self {
+            config,
+            source: source.into(),
+            tls,
+        }
+    }
+    pub fn config(&self) -> &tokio_postgres::Config {
+        &self.config
+    }
+    pub fn source(&self) -> &PgPassSource {
+        &self.source
+    }
+}
+
+impl<T> managed::Manager for PgPassManager<T>
+where
+    T: MakeTlsConnect<Socket> + Clone + Sync + Send + 'static,
+    T::Stream: Sync + Send,
+    T::TlsConnect: Sync + Send,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    type Type = Client;
+    type Error = ConnectError;
+
+    async fn create(&self) -> Result<Client, ConnectError> {
+        connect_with_retry(
+            &self.source,
+            |pgpass| apply(pgpass, &self.config),
+            |config| connect_tokio(config, self.tls.clone()),
+        )
+        .await
+    }
+
+    async fn recycle(&self, client: &mut Client, _: &Metrics) -> RecycleResult<ConnectError> {
+        if client.is_closed() {
+            return Err(RecycleError::message("Connection closed"));
+        }
+        client
+            .simple_query("")
+            .await
+            .map_err(|e| RecycleError::Backend(e.into()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        pgpass::{watch::WatchedPgPass, LoadOptions, PgPass},
+        pool::fake_server::FakeServer,
+        test_utils::TempFile,
+    };
+
+    fn config(server: &FakeServer) -> tokio_postgres::Config {
+        let mut config = tokio_postgres::Config::new();
+        config
+            .host("127.0.0.1")
+            .port(server.port())
+            .dbname("database")
+            .user("username");
+        config
+    }
+
+    #[tokio::test]
+    async fn connects_with_pgpass_password() -> anyhow::Result<()> {
+        let server = FakeServer::start("secret");
+        let pgpass: PgPass = "127.0.0.1:*:database:username:secret".parse()?;
+        let pool = Pool::builder(PgPassManager::new(
+            config(&server),
+            pgpass,
+            tokio_postgres::NoTls,
+        ))
+        .max_size(1)
+        .build()?;
+
+        let client = pool.get().await?;
+        drop(client);
+        // The connection is recycled rather than reopened
+        let _client = pool.get().await?;
+        assert_eq!(server.attempts(), ["secret"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn static_source_does_not_retry() -> anyhow::Result<()> {
+        let server = FakeServer::start("secret");
+        let pgpass: PgPass = "127.0.0.1:*:database:username:wrong".parse()?;
+        let pool = Pool::builder(PgPassManager::new(
+            config(&server),
+            pgpass,
+            tokio_postgres::NoTls,
+        ))
+        .build()?;
+
+        match pool.get().await {
+            Err(managed::PoolError::Backend(ConnectError::Postgres(e))) => {
+                assert_eq!(
+                    e.code(),
+                    Some(&tokio_postgres::error::SqlState::INVALID_PASSWORD)
+                )
+            }
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert_eq!(server.attempts(), ["wrong"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reloads_after_rotation() -> anyhow::Result<()> {
+        let server = FakeServer::start("old");
+        let file = TempFile::new("deadpool-rotation", "127.0.0.1:*:database:username:old\n");
+        // Long enough that the watcher never notices the change by itself
+        let watched =
+            WatchedPgPass::open_with(&file.0, &LoadOptions::default(), Duration::from_secs(3600))?;
+        let pool = Pool::builder(PgPassManager::new(
+            config(&server),
+            watched,
+            tokio_postgres::NoTls,
+        ))
+        .max_size(1)
+        .build()?;
+        let client = pool.get().await?;
+
+        file.replace("127.0.0.1:*:database:username:new\n");
+        server.set_password("new");
+        // Remove the connection from the pool, so a new one is opened
+        drop(managed::Object::take(client));
+        let _client = pool.get().await?;
+        assert_eq!(server.attempts(), ["old", "old", "new"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unchanged_password_is_not_retried() -> anyhow::Result<()> {
+        let server = FakeServer::start("secret");
+        let file = TempFile::new(
+            "deadpool-unchanged",
+            "127.0.0.1:*:database:username:wrong\n",
+        );
+        let watched = WatchedPgPass::open(&file.0)?;
+        let pool = Pool::builder(PgPassManager::new(
+            config(&server),
+            watched,
+            tokio_postgres::NoTls,
+        ))
+        .build()?;
+
+        assert!(pool.get().await.is_err());
+        assert_eq!(server.attempts(), ["wrong"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+//! Connection pools which look up the password every time they open a
+//! connection, rather than capturing [`Credentials`][crate::Credentials] once. A long-lived pool
+//! then picks up a rotated password without being rebuilt.
+//!
+//! Each integration requires it's own feature:
+//! - `deadpool`: [`PgPassManager`][deadpool::PgPassManager], which is given a
+//!   base config without a password. The password is set with
+//!   [`PgPass::apply_to`].
+//! - `r2d2` and `bb8`: [`r2d2::PgPassConnectionManager`] and
+//!   [`bb8::PgPassConnectionManager`], which are given a
+//!   [`CredentialQuery`][crate::pgpass::CredentialQuery]. The config is built
+//!   from the credentials the query finds.
+//!
+//! The password is looked up from a [`PgPassSource`] for each new connection,
+//! and is never stored by the pool. If the server rejects the password and the
+//! source is a [`WatchedPgPass`], the file is reloaded, and the connection is
+//! retried once if the password changed.
+
+use std::sync::Arc;
+
+use log::debug;
+use thiserror::Error;
+use tokio_postgres::error::SqlState;
+
+use crate::pgpass::{
+    config::{private::Sealed, ApplyError, ConnectionConfig},
+    watch::WatchedPgPass,
+    FindError, LoadError, PgPass,
+};
+
+#[cfg(feature = "bb8")]
+pub mod bb8;
+#[cfg(feature = "deadpool")]
+pub mod deadpool;
+#[cfg(feature = "r2d2")]
+pub mod r2d2;
+
+/// Where a pool looks up passwords.
+#[derive(Debug, Clone)]
+pub enum PgPassSource {
+    /// A file which was loaded once. Changes to the file are not seen.
+    Static(Arc<PgPass>),
+    /// A file which is reloaded when it changes, and when the server rejects a
+    /// password.
+    Watched(Arc<WatchedPgPass>),
+}
+impl PgPassSource {
+    /// The current contents of the file.
+    pub fn snapshot(&self) -> Arc<PgPass> {
+        match self {
+            Self::Static(pgpass) => pgpass.clone(),
+            Self::Watched(watched) => watched.snapshot(),
+        }
+    }
+    /// Reload the file immediately. Returns `None` if the source can't be
+    /// reloaded.
+    pub fn reload(&self) -> Result<Option<Arc<PgPass>>, Arc<LoadError>> {
+        match self {
+            Self::Static(_) => Ok(None),
+            Self::Watched(watched) => watched.reload_now().map(Some),
+        }
+    }
+}
+impl From<PgPass> for PgPassSource {
+    fn from(value: PgPass) -> Self {
+        Self::Static(Arc::new(value))
+    }
+}
+impl From<Arc<PgPass>> for PgPassSource {
+    fn from(value: Arc<PgPass>) -> Self {
+        Self::Static(value)
+    }
+}
+impl From<WatchedPgPass> for PgPassSource {
+    fn from(value: WatchedPgPass) -> Self {
+        Self::Watched(Arc::new(value))
+    }
+}
+impl From<Arc<WatchedPgPass>> for PgPassSource {
+    fn from(value: Arc<WatchedPgPass>) -> Self {
+        Self::Watched(value)
+    }
+}
+
+/// An error encountered while opening a pooled connection. It is safe to log or
+/// display this error; it will not contain passwords.
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    #[error("{0}")]
+    Apply(#[from] ApplyError),
+    #[error("{0}")]
+    Find(#[from] FindError),
+    /// No pattern in the pgpass file matches the query of the manager.
+    #[error("No pattern in the pgpass file matches the query.")]
+    NotFound,
+    /// The server rejected the password, and reloading the file failed.
+    #[error("Failed to reload the pgpass file: {0}")]
+    Reload(Arc<LoadError>),
+    #[error("{0}")]
+    Postgres(#[from] tokio_postgres::Error),
+}
+
+/// Set the password of a copy of `config` from `pgpass`.
+#[cfg(feature = "deadpool")]
+fn apply<C: ConnectionConfig + Clone>(pgpass: &PgPass, config: &C) -> Result<C, ConnectError> {
+    let mut config = config.clone();
+    pgpass.apply_to(&mut config)?;
+    Ok(config)
+}
+
+/// Build a config from the credentials `query` finds in `pgpass`.
+#[cfg(any(feature = "r2d2", feature = "bb8"))]
+fn find<C: From<crate::Credentials>>(
+    pgpass: &PgPass,
+    query: &crate::pgpass::CredentialQuery,
+) -> Result<C, ConnectError> {
+    let creds = pgpass.find(query)?.ok_or(ConnectError::NotFound)?;
+    Ok(creds.into())
+}
+
+/// Whether the server rejected the password.
+fn is_auth_failure(error: &tokio_postgres::Error) -> bool {
+    error.code() == Some(&SqlState::INVALID_PASSWORD)
+}
+
+/// Reload `source` after `first` was rejected, and build the config to retry
+/// with. Returns `None` if there is nothing to retry, because the source can't
+/// be reloaded or the password didn't change.
+fn retry_config<C: ConnectionConfig>(
+    reloaded: Result<Option<Arc<PgPass>>, Arc<LoadError>>,
+    first: &C,
+    resolve: impl Fn(&PgPass) -> Result<C, ConnectError>,
+) -> Result<Option<C>, ConnectError> {
+    let Some(reloaded) = reloaded.map_err(ConnectError::Reload)? else {
+        return Ok(None);
+    };
+    let retry = resolve(&reloaded)?;
+    if Sealed::password(&retry) == Sealed::password(first) {
+        return Ok(None);
+    }
+    debug!("Password was rejected; retrying with the reloaded pgpass file.");
+    Ok(Some(retry))
+}
+
+/// Open a connection with the config `resolve` builds from `source`, and retry
+/// once with a reloaded file if the password is rejected.
+#[cfg(feature = "r2d2")]
+fn connect_with_retry_blocking<T, C: ConnectionConfig + Clone>(
+    source: &PgPassSource,
+    resolve: impl Fn(&PgPass) -> Result<C, ConnectError>,
+    connect: impl Fn(C) -> Result<T, tokio_postgres::Error>,
+) -> Result<T, ConnectError> {
+    let first = resolve(&source.snapshot())?;
+    let error = match connect(first.clone()) {
+        Err(e) if is_auth_failure(&e) => e,
+        result => return Ok(result?),
+    };
+    match retry_config(source.reload(), &first, resolve)? {
+        Some(retry) => Ok(connect(retry)?),
+        None => Err(error.into()),
+    }
+}
+
+/// The same as [`connect_with_retry_blocking`], but the file is reloaded on a
+/// blocking thread.
+#[cfg(any(feature = "deadpool", feature = "bb8"))]
+async fn connect_with_retry<T, C, F>(
+    source: &PgPassSource,
+    resolve: impl Fn(&PgPass) -> Result<C, ConnectError>,
+    connect: impl Fn(C) -> F,
+) -> Result<T, ConnectError>
+where
+    C: ConnectionConfig + Clone,
+    F: std::future::Future<Output = Result<T, tokio_postgres::Error>>,
+{
+    let first = resolve(&source.snapshot())?;
+    let error = match connect(first.clone()).await {
+        Err(e) if is_auth_failure(&e) => e,
+        result => return Ok(result?),
+    };
+    let reload = source.clone();
+    let reloaded = tokio::task::spawn_blocking(move || reload.reload())
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+    match retry_config(reloaded, &first, resolve)? {
+        Some(retry) => Ok(connect(retry).await?),
+        None => Err(error.into()),
+    }
+}
+
+/// Connect with `tokio_postgres`, and drive the connection on a spawned task.
+#[cfg(any(feature = "deadpool", feature = "bb8"))]
+async fn connect_tokio<T>(
+    config: tokio_postgres::Config,
+    tls: T,
+) -> Result<tokio_postgres::Client, tokio_postgres::Error>
+where
+    T: tokio_postgres::tls::MakeTlsConnect<tokio_postgres::Socket>,
+    T::Stream: Send + 'static,
+{
+    let (client, connection) = config.connect(tls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::warn!("Pooled Postgres connection failed: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+#[cfg(test)]
+pub(crate) mod fake_server {
+    //! A Postgres server which only knows how to authenticate a cleartext
+    //! password, and answer empty queries.
+
+    use std::{
+        io::{self, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    const SSL_REQUEST: u32 = 80877103;
+
+    #[derive(Default)]
+    struct State {
+        password: String,
+        attempts: Vec<String>,
+    }
+
+    pub struct FakeServer {
+        port: u16,
+        state: Arc<Mutex<State>>,
+    }
+    impl FakeServer {
+        /// Start a server which accepts `password`.
+        pub fn start(password: &str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let state = Arc::new(Mutex::new(State {
+                password: password.to_string(),
+                ..Default::default()
+            }));
+            let shared = state.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let state = shared.clone();
+                    thread::spawn(move || {
+                        let _ = serve(stream, &state);
+                    });
+                }
+            });
+            Self { port, state }
+        }
+        pub fn port(&self) -> u16 {
+            self.port
+        }
+        /// Change the password the server accepts.
+        pub fn set_password(&self, password: &str) {
+            self.state.lock().unwrap().password = password.to_string();
+        }
+        /// Every password clients have sent, in order.
+        pub fn attempts(&self) -> Vec<String> {
+            self.state.lock().unwrap().attempts.clone()
+        }
+    }
+
+    fn read_u32(stream: &mut TcpStream) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+    fn read_body(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+        let len = read_u32(stream)? as usize;
+        let mut body = vec![0; len - 4];
+        stream.read_exact(&mut body)?;
+        Ok(body)
+    }
+    fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![tag];
+        message.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+        message.extend_from_slice(body);
+        message
+    }
+
+    fn serve(mut stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+        let startup = read_body(&mut stream)?;
+        if startup[..] == SSL_REQUEST.to_be_bytes() {
+            // Refuse SSL, then read the real startup message
+            stream.write_all(b"N")?;
+            read_body(&mut stream)?;
+        }
+
+        // AuthenticationCleartextPassword
+        stream.write_all(&message(b'R', &3u32.to_be_bytes()))?;
+        let mut tag = [0];
+        stream.read_exact(&mut tag)?;
+        let body = read_body(&mut stream)?;
+        let password =
+            String::from_utf8_lossy(body.strip_suffix(b"\0").unwrap_or(&body)).into_owned();
+
+        let accepted = {
+            let mut state = state.lock().unwrap();
+            state.attempts.push(password.clone());
+            state.password == password
+        };
+        if !accepted {
+            let mut fields = Vec::new();
+            for (code, value) in [
+                (b'S', "FATAL"),
+                (b'V', "FATAL"),
+                (b'C', "28P01"),
+                (b'M', "password authentication failed"),
+            ] {
+                fields.push(code);
+                fields.extend_from_slice(value.as_bytes());
+                fields.push(0);
+            }
+            fields.push(0);
+            return stream.write_all(&message(b'E', &fields));
+        }
+
+        // AuthenticationOk, then ReadyForQuery
+        stream.write_all(&message(b'R', &0u32.to_be_bytes()))?;
+        stream.write_all(&message(b'Z', b"I"))?;
+        loop {
+            stream.read_exact(&mut tag)?;
+            read_body(&mut stream)?;
+            match tag[0] {
+                // Query: EmptyQueryResponse, then ReadyForQuery
+                b'Q' => {
+                    stream.write_all(&message(b'I', &[]))?;
+                    stream.write_all(&message(b'Z', b"I"))?;
+                }
+                // Terminate
+                b'X' => return Ok(()),
+                _ => (),
+            }
+        }
+    }
+}
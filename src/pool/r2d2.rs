@@ -0,0 +1,149 @@
+//! An `r2d2` connection manager for `postgres`. Requires the `r2d2` feature.
+
+use ::r2d2::ManageConnection;
+use postgres::{
+    tls::{MakeTlsConnect, TlsConnect},
+    Client, Socket,
+};
+
+use super::{connect_with_retry_blocking, find, ConnectError, PgPassSource};
+use crate::pgpass::CredentialQuery;
+
+/// A pool of `postgres` clients, which looks up the password every time it
+/// connects.
+pub type Pool<T> = ::r2d2::Pool<PgPassConnectionManager<T>>;
+
+/// A [`ManageConnection`] which connects with the [`Credentials`][crate::Credentials]
+/// a query finds in a [`PgPassSource`]. See the [module documentation](super).
+///
+/// ```no_run
+/// # use postgres_secrets::{pgpass::{watch::WatchedPgPass, CredentialQuery}, pool::r2d2::*};
+/// # fn main() -> anyhow::Result<()> {
+/// let query = CredentialQuery::default()
+///     .hostname("db.example.com")?
+///     .username("alice")?;
+/// let watched = WatchedPgPass::open("/run/secrets/pgpass")?;
+/// let manager = PgPassConnectionManager::new(query, watched, postgres::NoTls);
+/// let pool = Pool::builder().max_size(16).build(manager)?;
+///
+/// let mut client = pool.get()?;
+/// client.simple_query("SELECT 1")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PgPassConnectionManager<T> {
+    query: CredentialQuery,
+    source: PgPassSource,
+    tls: T,
+}
+impl<T> PgPassConnectionManager<T> {
+    pub fn new(query: CredentialQuery, source: impl Into<PgPassSource>, tls: T) -> Self {
+        Self {
+            query,
+            source: source.into(),
+            tls,
+        }
+    }
+    pub fn query(&self) -> &CredentialQuery {
+        &self.query
+    }
+    pub fn source(&self) -> &PgPassSource {
+        &self.source
+    }
+}
+
+impl<T> ManageConnection for PgPassConnectionManager<T>
+where
+    T: MakeTlsConnect<Socket> + Clone + Sync + Send + 'static,
+    T::TlsConnect: Send,
+    T::Stream: Send,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    type Connection = Client;
+    type Error = ConnectError;
+
+    fn connect(&self) -> Result<Client, ConnectError> {
+        connect_with_retry_blocking(
+            &self.source,
+            |pgpass| find::<postgres::Config>(pgpass, &self.query),
+            |config| config.connect(self.tls.clone()),
+        )
+    }
+
+    fn is_valid(&self, client: &mut Client) -> Result<(), ConnectError> {
+        client.simple_query("")?;
+        Ok(())
+    }
+
+    fn has_broken(&self, client: &mut Client) -> bool {
+        client.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        pgpass::{watch::WatchedPgPass, LoadOptions, PgPass},
+        pool::fake_server::FakeServer,
+        test_utils::TempFile,
+    };
+
+    fn query(server: &FakeServer) -> CredentialQuery {
+        CredentialQuery::default()
+            .hostname("127.0.0.1")
+            .unwrap()
+            .port(server.port())
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username("username")
+            .unwrap()
+    }
+
+    #[test]
+    fn connects_with_pgpass_password() -> anyhow::Result<()> {
+        let server = FakeServer::start("secret");
+        let pgpass: PgPass = "127.0.0.1:*:database:username:secret".parse()?;
+        let manager = PgPassConnectionManager::new(query(&server), pgpass, postgres::NoTls);
+        let pool = Pool::builder().max_size(1).build(manager)?;
+
+        pool.get()?.simple_query("")?;
+        assert_eq!(server.attempts(), ["secret"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_matching_pattern() -> anyhow::Result<()> {
+        let server = FakeServer::start("secret");
+        let pgpass: PgPass = "other.example.com:*:*:*:secret".parse()?;
+        let manager = PgPassConnectionManager::new(query(&server), pgpass, postgres::NoTls);
+
+        assert!(matches!(manager.connect(), Err(ConnectError::NotFound)));
+        assert!(server.attempts().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reloads_after_rotation() -> anyhow::Result<()> {
+        let server = FakeServer::start("old");
+        let file = TempFile::new("r2d2-rotation", "127.0.0.1:*:database:username:old\n");
+        let watched =
+            WatchedPgPass::open_with(&file.0, &LoadOptions::default(), Duration::from_secs(3600))?;
+        let manager = PgPassConnectionManager::new(query(&server), watched, postgres::NoTls);
+
+        manager.connect()?;
+
+        file.replace("127.0.0.1:*:database:username:new\n");
+        server.set_password("new");
+        manager.connect()?;
+        assert_eq!(server.attempts(), ["old", "old", "new"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+//! A credential agent, like `ssh-agent` for database passwords. Requires the
+//! `agent` feature, and a Unix platform.
+//!
+//! An [`Agent`] holds credentials in memory, and answers requests over a Unix
+//! domain socket using the [helper protocol](super::helper). Only processes
+//! running as the same user as the agent may connect; this is checked with
+//! `SO_PEERCRED` (or `getpeereid` on the BSDs). A pgpass file which is slow or
+//! inconvenient to load, for example because it must be decrypted, can be
+//! loaded once by the agent, and short-lived processes can then look up their
+//! credentials with an [`AgentClient`].
+//!
+//! Every pattern the agent holds has a lifetime, after which the agent forgets
+//! it. While serving, expired patterns are purged periodically, even if no
+//! requests arrive. The passwords of patterns which are forgotten are
+//! overwritten before they are dropped.
+
+use std::{
+    env, fs,
+    io::{self, BufReader},
+    os::unix::{
+        fs::PermissionsExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use zeroize::Zeroize;
+
+use super::{
+    helper::{
+        get_reply, same_fields, serve_requests, stored_pattern, Action, HelperClient, HelperError,
+        Reply, Request,
+    },
+    CredentialSource, SourceError,
+};
+use crate::{
+    pgpass::{pattern::HasPasswordTrue, CredentialPattern, CredentialQuery},
+    Credentials, PgPass,
+};
+
+/// The environment variable [`AgentClient::from_env`] reads the socket path from.
+pub const SOCKET_VARIABLE: &str = "PGPASS_AGENT_SOCK";
+/// How long an agent holds a pattern, by default.
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How often [`Agent::serve`] purges expired patterns.
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long [`Agent::serve`] waits after failing to accept a connection, so that
+/// a persistent failure (such as running out of file descriptors) doesn't spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Holds credentials in memory, and serves them to processes of the same user.
+/// See the [module documentation](self).
+///
+/// `get` requests are answered with [`PgPass::find`]. `store` requests add a
+/// pattern with a fresh lifetime, taking precedence over the others, and
+/// `erase` requests remove the pattern with exactly the same fields.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use postgres_secrets::{PgPass, source::agent::*};
+/// # fn main() -> anyhow::Result<()> {
+/// let agent = Agent::new(PgPass::load()?).with_lifetime(Duration::from_secs(15 * 60));
+/// let listener = bind("/run/user/1000/pgpass-agent.sock")?;
+/// agent.serve(&listener);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Agent {
+    entries: Mutex<Entries>,
+    lifetime: Duration,
+}
+#[derive(Debug)]
+struct Entries {
+    pgpass: PgPass,
+    /// When each pattern was added, aligned with the patterns of `pgpass`.
+    added: Vec<Instant>,
+}
+impl Entries {
+    /// Keep only the patterns for which `f` returns `true`, wiping the passwords
+    /// of the others.
+    fn retain(&mut self, mut f: impl FnMut(&CredentialPattern<HasPasswordTrue>, Instant) -> bool) {
+        let mut added = self.added.iter();
+        let mut keep = Vec::with_capacity(self.added.len());
+        self.pgpass.retain_mut(|pattern| {
+            let kept = f(pattern, *added.next().expect("entries are aligned"));
+            if !kept {
+                pattern.password.zeroize();
+            }
+            keep.push(kept);
+            kept
+        });
+        let mut keep = keep.into_iter();
+        self.added
+            .retain(|_| keep.next().expect("entries are aligned"));
+    }
+    /// Forget the patterns which were added at least `lifetime` before `now`.
+    fn purge(&mut self, now: Instant, lifetime: Duration) {
+        self.retain(|_, added| now.saturating_duration_since(added) < lifetime);
+    }
+}
+impl Drop for Entries {
+    fn drop(&mut self) {
+        self.retain(|_, _| false);
+    }
+}
+impl Agent {
+    /// Hold the patterns of `pgpass`, for the [default lifetime][DEFAULT_LIFETIME].
+    pub fn new(pgpass: PgPass) -> Self {
+        let added = vec![Instant::now(); pgpass.len()];
+        Self {
+            entries: Mutex::new(Entries { pgpass, added }),
+            lifetime: DEFAULT_LIFETIME,
+        }
+    }
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+    /// Set how long patterns are held, measured from when they were added.
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.set_lifetime(lifetime);
+        self
+    }
+    /// The number of patterns which have not expired.
+    pub fn len(&self) -> usize {
+        self.entries(Instant::now()).pgpass.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Accept connections from `listener` forever, serving each on it's own
+    /// thread. Connections from other users are refused, and failures to accept
+    /// a connection are logged. Another thread purges expired patterns every
+    /// second.
+    pub fn serve(&self, listener: &UnixListener) -> ! {
+        thread::scope(|scope| {
+            scope.spawn(|| loop {
+                thread::sleep(PURGE_INTERVAL);
+                drop(self.entries(Instant::now()));
+            });
+            loop {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("pgpass agent failed to accept a connection: {}", e);
+                        thread::sleep(ACCEPT_BACKOFF);
+                        continue;
+                    }
+                };
+                scope.spawn(move || {
+                    if let Err(e) = self.serve_connection(stream) {
+                        warn!("pgpass agent connection failed: {}", e);
+                    }
+                });
+            }
+        })
+    }
+    /// Serve a single connection, if it is from the same user as the agent.
+    pub fn serve_connection(&self, stream: UnixStream) -> Result<(), HelperError> {
+        let peer = peer_uid(&stream)?;
+        if peer != current_uid() {
+            warn!("pgpass agent refused a connection from uid {}", peer);
+            return Ok(());
+        }
+        let reader = BufReader::new(stream.try_clone()?);
+        serve_requests(reader, stream, |request| {
+            self.handle(request, Instant::now())
+        })
+    }
+
+    /// Lock the entries, forgetting any which had expired by `now`.
+    fn entries(&self, now: Instant) -> std::sync::MutexGuard<'_, Entries> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.purge(now, self.lifetime);
+        entries
+    }
+    /// Answer a request received at `now`.
+    fn handle(&self, request: &Request, now: Instant) -> Reply {
+        let mut entries = self.entries(now);
+        let query = &request.query;
+        match request.action {
+            Action::Get => get_reply(&entries.pgpass, query),
+            Action::Store => {
+                let pattern = stored_pattern(request)?;
+                entries.retain(|p, _| !same_fields(p, query));
+                entries.pgpass.insert(0, pattern);
+                entries.added.insert(0, now);
+                Ok(Vec::new())
+            }
+            Action::Erase => {
+                entries.retain(|p, _| !same_fields(p, query));
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+/// Listen on a new socket at `path`, which only it's owner may read or write.
+/// Fails if `path` already exists.
+pub fn bind<P: AsRef<Path>>(path: P) -> Result<UnixListener, io::Error> {
+    let listener = UnixListener::bind(&path)?;
+    // Connections are checked regardless, so a connection made before this
+    // takes effect is still refused if it's from another user.
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn current_uid() -> libc::uid_t {
+    // SAFETY: geteuid has no preconditions, and can't fail.
+    unsafe { libc::geteuid() }
+}
+
+/// The effective uid of the process on the other end of `stream`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t, io::Error> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` describe a valid buffer of the size SO_PEERCRED
+    // writes, and the descriptor is open for the lifetime of `stream`.
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+/// The effective uid of the process on the other end of `stream`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t, io::Error> {
+    let mut uid = 0;
+    let mut gid = 0;
+    // SAFETY: `uid` and `gid` are valid for writes, and the descriptor is open
+    // for the lifetime of `stream`.
+    let result = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// A [`CredentialSource`] which looks up credentials in an [`Agent`]. Each
+/// request opens a new connection, so the agent may be restarted between them.
+///
+/// ```no_run
+/// # use postgres_secrets::{pgpass::*, source::{agent::*, *}};
+/// # fn main() -> anyhow::Result<()> {
+/// let agent = AgentClient::from_env().expect("PGPASS_AGENT_SOCK is set");
+/// let query = CredentialQuery::default()
+///     .hostname("db.example.com")?
+///     .database("orders")?
+///     .username("alice")?;
+/// let creds = agent.lookup(&query)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AgentClient {
+    path: PathBuf,
+}
+impl AgentClient {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+    /// Connect to the socket named by the [`PGPASS_AGENT_SOCK`][SOCKET_VARIABLE]
+    /// environment variable, if it is set.
+    pub fn from_env() -> Option<Self> {
+        env::var_os(SOCKET_VARIABLE)
+            .filter(|path| !path.is_empty())
+            .map(Self::new)
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    fn connect(&self) -> Result<HelperClient<BufReader<UnixStream>, UnixStream>, HelperError> {
+        let stream = UnixStream::connect(&self.path)?;
+        Ok(HelperClient::new(
+            BufReader::new(stream.try_clone()?),
+            stream,
+        ))
+    }
+    /// Add `pattern` to the agent, taking precedence over the patterns it
+    /// already holds.
+    pub fn add(&self, pattern: &CredentialPattern<HasPasswordTrue>) -> Result<(), HelperError> {
+        self.connect()?.store(pattern)
+    }
+    /// Remove the pattern with exactly the fields of `query` from the agent.
+    pub fn remove(&self, query: &CredentialQuery) -> Result<(), HelperError> {
+        self.connect()?.erase(query)
+    }
+}
+impl CredentialSource for AgentClient {
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        self.connect()?.get(query)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn query() -> CredentialQuery {
+        CredentialQuery::default()
+            .hostname("example.com")
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username("username")
+            .unwrap()
+    }
+
+    /// Start serving `agent` on a socket in the temporary directory.
+    fn start(name: &str, agent: Agent) -> AgentClient {
+        let path = env::temp_dir().join(format!("pgpass-agent-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = bind(&path).unwrap();
+        thread::spawn(move || agent.serve(&listener));
+        AgentClient::new(path)
+    }
+
+    #[test]
+    fn lookup_add_remove() -> anyhow::Result<()> {
+        let client = start("lookup", Agent::new("*:*:*:*:loaded".parse()?));
+        assert_eq!(
+            fs::metadata(client.path())?.permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(client.lookup(&query())?.unwrap().password, "loaded");
+
+        let added = CredentialPattern::default()
+            .hostname("example.com")?
+            .password("added")?;
+        client.add(&added)?;
+        assert_eq!(client.lookup(&query())?.unwrap().password, "added");
+
+        client.remove(&CredentialQuery::default().hostname("example.com")?)?;
+        assert_eq!(client.lookup(&query())?.unwrap().password, "loaded");
+
+        let _ = fs::remove_file(client.path());
+        Ok(())
+    }
+
+    #[test]
+    fn patterns_expire() -> anyhow::Result<()> {
+        let agent = Agent::new("*:*:*:*:loaded".parse()?).with_lifetime(Duration::from_secs(60));
+        // The loaded pattern was added no later than this
+        let start = Instant::now();
+        let other = CredentialQuery::default()
+            .hostname("other.example.com")?
+            .database("database")?
+            .username("username")?;
+        let password = |query: &CredentialQuery, at: Duration| {
+            let request = Request {
+                action: Action::Get,
+                query: query.clone(),
+                password: None,
+            };
+            let reply = agent.handle(&request, start + at).unwrap();
+            reply
+                .into_iter()
+                .find(|(key, _)| *key == "password")
+                .map(|(_, value)| value)
+        };
+
+        let store = Request {
+            action: Action::Store,
+            query: CredentialQuery::default().hostname("other.example.com")?,
+            password: Some("added".to_string()),
+        };
+        agent
+            .handle(&store, start + Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(agent.entries(start).pgpass.len(), 2);
+
+        // Each pattern's lifetime starts when it was added
+        let at = Duration::from_secs(75);
+        assert_eq!(password(&query(), at), None);
+        assert_eq!(password(&other, at).as_deref(), Some("added"));
+        let at = Duration::from_secs(95);
+        assert_eq!(password(&other, at), None);
+        assert!(agent.entries(start + at).pgpass.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn peer_is_checked() -> anyhow::Result<()> {
+        let (a, b) = UnixStream::pair()?;
+        assert_eq!(peer_uid(&a)?, current_uid());
+        assert_eq!(peer_uid(&b)?, current_uid());
+
+        let client = AgentClient::new("/nonexistent/pgpass-agent.sock");
+        assert!(matches!(
+            client.lookup(&query()),
+            Err(SourceError::Helper(HelperError::Io(_)))
+        ));
+
+        Ok(())
+    }
+}
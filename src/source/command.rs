@@ -0,0 +1,378 @@
+//! Looking up passwords by running a command, such as a password manager's CLI.
+//! See [`CommandSource`].
+
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use super::{credentials_for, CredentialSource, SourceError};
+use crate::{
+    pgpass::{CredentialQuery, FindError},
+    Credentials,
+};
+
+/// How long a command may run before it is killed, by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many bytes a command may write to stdout, by default. This is the same
+/// as the longest line [`LoadOptions`][crate::pgpass::LoadOptions] accepts.
+pub const DEFAULT_MAX_OUTPUT: u64 = 64 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How a [`CommandSource`] passes the query to the command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CommandInput {
+    /// As the `PGHOST`, `PGPORT`, `PGDATABASE` and `PGUSER` environment
+    /// variables, the same as `libpq` would read them.
+    #[default]
+    Environment,
+    /// On stdin, as `key=value` lines followed by a blank line, like a
+    /// `git` credential helper. The keys are `host`, `port`, `database` and
+    /// `user`.
+    Stdin,
+}
+
+/// A [`CredentialSource`] which runs a command to get the password, such as
+/// `pass`, `op` or `gopass`. The first line the command writes to stdout is the
+/// password. If it writes nothing, it has no credentials for the query.
+///
+/// The query must have a hostname, database and username; the port defaults
+/// to the [default port][crate::DEFAULT_PORT]. The command's stderr is
+/// inherited, so that it can report errors or prompt the user.
+///
+/// ```no_run
+/// # use postgres_secrets::{pgpass::*, source::{command::*, *}};
+/// # fn main() -> anyhow::Result<()> {
+/// let source = CommandSource::shell("pass show \"postgres/$PGHOST/$PGUSER\"");
+/// let query = CredentialQuery::default()
+///     .hostname("db.example.com")?
+///     .database("orders")?
+///     .username("alice")?;
+/// let creds = source.lookup(&query)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSource {
+    program: OsString,
+    args: Vec<OsString>,
+    input: CommandInput,
+    timeout: Duration,
+    max_output: u64,
+}
+impl CommandSource {
+    /// Run `program` directly, without a shell.
+    pub fn new(program: impl Into<OsString>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            input: CommandInput::default(),
+            timeout: DEFAULT_TIMEOUT,
+            max_output: DEFAULT_MAX_OUTPUT,
+        }
+    }
+    /// Run `command` with `sh -c`, so that it can use the environment variables
+    /// of the query.
+    pub fn shell(command: impl Into<OsString>) -> Self {
+        Self::new("sh").with_arg("-c").with_arg(command)
+    }
+    pub fn program(&self) -> &OsString {
+        &self.program
+    }
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+    pub fn add_arg(&mut self, arg: impl Into<OsString>) {
+        self.args.push(arg.into());
+    }
+    pub fn with_arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.add_arg(arg);
+        self
+    }
+    pub fn input(&self) -> CommandInput {
+        self.input
+    }
+    pub fn set_input(&mut self, input: CommandInput) {
+        self.input = input;
+    }
+    pub fn with_input(mut self, input: CommandInput) -> Self {
+        self.set_input(input);
+        self
+    }
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+    /// Set how long the command may run before it is killed.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.set_timeout(timeout);
+        self
+    }
+    pub fn max_output(&self) -> u64 {
+        self.max_output
+    }
+    /// Set how many bytes the command may write to stdout. A command which writes
+    /// more fails with [`CommandError::OutputTooLarge`].
+    pub fn set_max_output(&mut self, max_output: u64) {
+        self.max_output = max_output;
+    }
+    pub fn with_max_output(mut self, max_output: u64) -> Self {
+        self.set_max_output(max_output);
+        self
+    }
+
+    /// Run the command for the fields of `creds`, and return the password it
+    /// printed, if any.
+    fn run(&self, creds: &Credentials) -> Result<Option<String>, CommandError> {
+        let fields = [
+            ("PGHOST", "host", creds.hostname.clone()),
+            ("PGPORT", "port", creds.port.to_string()),
+            ("PGDATABASE", "database", creds.database.clone()),
+            ("PGUSER", "user", creds.username.clone()),
+        ];
+
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        // Don't let the command see a password meant for something else
+        command.env_remove("PGPASSWORD");
+        let stdin = match self.input {
+            CommandInput::Environment => {
+                for (variable, _, value) in &fields {
+                    command.env(variable, value);
+                }
+                command.stdin(Stdio::null());
+                None
+            }
+            CommandInput::Stdin => {
+                command.stdin(Stdio::piped());
+                let mut input = String::new();
+                for (_, key, value) in &fields {
+                    input.push_str(&format!("{}={}\n", key, value));
+                }
+                input.push('\n');
+                Some(input)
+            }
+        };
+
+        let mut child = command.spawn().map_err(CommandError::Spawn)?;
+        let deadline = Instant::now() + self.timeout;
+
+        // Read and write on other threads, so that a command which fills a pipe
+        // can't block us past the deadline.
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            thread::spawn(move || {
+                // The command may exit without reading all of it's input
+                let _ = pipe.write_all(input.as_bytes());
+            });
+        }
+        let (tx, rx) = mpsc::channel();
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let limit = self.max_output;
+        thread::spawn(move || {
+            // Read one byte more than the limit, so that we can tell if it was
+            // exceeded
+            let mut reader = stdout.take(limit.saturating_add(1));
+            let mut output = Vec::new();
+            let result = match reader.read_to_end(&mut output) {
+                Ok(_) if output.len() as u64 > limit => Err(CommandError::OutputTooLarge { limit }),
+                Ok(_) => Ok(output),
+                Err(e) => Err(CommandError::Io(e)),
+            };
+            let _ = tx.send(result);
+            // Only close the pipe once the result is sent, so that it's
+            // available if the command is killed by writing to it
+            drop(reader);
+        });
+
+        let status = self.wait(&mut child, deadline)?;
+        if !status.success() {
+            // The command may have been killed by the closed pipe
+            if let Ok(Err(e @ CommandError::OutputTooLarge { .. })) = rx.try_recv() {
+                return Err(e);
+            }
+            return Err(CommandError::Failed(status));
+        }
+        // A process the command started could still hold stdout open
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let output = match rx.recv_timeout(remaining) {
+            Ok(output) => output?,
+            Err(_) => return Err(CommandError::TimedOut(self.timeout)),
+        };
+
+        let output = String::from_utf8(output).map_err(|_| CommandError::InvalidOutput)?;
+        let password = output.lines().next().unwrap_or("");
+        if password.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(password.to_string()))
+        }
+    }
+    fn wait(&self, child: &mut Child, deadline: Instant) -> Result<ExitStatus, CommandError> {
+        loop {
+            if let Some(status) = child.try_wait().map_err(CommandError::Io)? {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CommandError::TimedOut(self.timeout));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+impl CredentialSource for CommandSource {
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        // Check the query first, so the command isn't run for nothing
+        let creds = credentials_for(query, String::new()).map_err(FindError::from)?;
+        Ok(self
+            .run(&creds)?
+            .map(|password| Credentials { password, ..creds }))
+    }
+}
+
+/// An error encountered while running the command of a [`CommandSource`]. It is
+/// safe to log or display this error; it will not contain passwords.
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Failed to run the password command: {0}")]
+    Spawn(io::Error),
+    #[error("The password command did not finish within {0:?}.")]
+    TimedOut(Duration),
+    /// The command exited unsuccessfully, for example because the password
+    /// manager doesn't have the entry.
+    #[error("The password command failed: {0}.")]
+    Failed(ExitStatus),
+    #[error("The output of the password command was not UTF-8.")]
+    InvalidOutput,
+    /// The command wrote more than [`max_output`][CommandSource::max_output]
+    /// bytes to stdout.
+    #[error("The password command wrote more than {limit} bytes.")]
+    OutputTooLarge { limit: u64 },
+    #[error("{0}")]
+    Io(io::Error),
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::pgpass::IncompleteCredential;
+
+    fn query() -> CredentialQuery {
+        CredentialQuery::default()
+            .hostname("example.com")
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username("username")
+            .unwrap()
+    }
+
+    #[test]
+    fn environment() -> anyhow::Result<()> {
+        let source = CommandSource::shell("echo \"$PGHOST:$PGPORT:$PGDATABASE:$PGUSER\"");
+        let creds = source.lookup(&query())?.unwrap();
+        assert_eq!(creds.password, "example.com:5432:database:username");
+        assert_eq!(creds.hostname, "example.com");
+        assert_eq!(creds.username, "username");
+
+        Ok(())
+    }
+
+    #[test]
+    fn stdin() -> anyhow::Result<()> {
+        let source = CommandSource::shell(
+            "while read -r line && [ -n \"$line\" ]; do printf '%s;' \"$line\"; done",
+        )
+        .with_input(CommandInput::Stdin);
+        let creds = source.lookup(&query())?.unwrap();
+        assert_eq!(
+            creds.password,
+            "host=example.com;port=5432;database=database;user=username;"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn first_line_is_the_password() -> anyhow::Result<()> {
+        let source = CommandSource::shell("printf 'secret\\nurl: example.com\\n'");
+        assert_eq!(source.lookup(&query())?.unwrap().password, "secret");
+
+        let source = CommandSource::new("true");
+        assert_eq!(source.lookup(&query())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let source = CommandSource::shell("exit 3");
+        match source.lookup(&query()) {
+            Err(SourceError::Command(CommandError::Failed(status))) => {
+                assert_eq!(status.code(), Some(3))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let source = CommandSource::shell("sleep 10").with_timeout(Duration::from_millis(50));
+        let started = Instant::now();
+        assert!(matches!(
+            source.lookup(&query()),
+            Err(SourceError::Command(CommandError::TimedOut(_)))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let source = CommandSource::new("/nonexistent/password-command");
+        assert!(matches!(
+            source.lookup(&query()),
+            Err(SourceError::Command(CommandError::Spawn(_)))
+        ));
+
+        let source = CommandSource::shell("echo hello").with_max_output(4);
+        assert!(matches!(
+            source.lookup(&query()),
+            Err(SourceError::Command(CommandError::OutputTooLarge {
+                limit: 4
+            }))
+        ));
+        let source = CommandSource::shell("yes");
+        assert!(matches!(
+            source.lookup(&query()),
+            Err(SourceError::Command(CommandError::OutputTooLarge {
+                limit: DEFAULT_MAX_OUTPUT
+            }))
+        ));
+        let source = CommandSource::shell("echo abc").with_max_output(4);
+        assert_eq!(source.lookup(&query()).unwrap().unwrap().password, "abc");
+
+        let source = CommandSource::shell("printf '\\377'");
+        assert!(matches!(
+            source.lookup(&query()),
+            Err(SourceError::Command(CommandError::InvalidOutput))
+        ));
+    }
+
+    #[test]
+    fn incomplete_query_does_not_run() {
+        let source = CommandSource::shell("echo secret");
+        let query = CredentialQuery::default().hostname("example.com").unwrap();
+        assert!(matches!(
+            source.lookup(&query),
+            Err(SourceError::Find(FindError::Incomplete(
+                IncompleteCredential::MissingDatabase
+            )))
+        ));
+    }
+}
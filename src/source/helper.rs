@@ -0,0 +1,644 @@
+//! A credential helper protocol, modeled on `git`'s. A client sends requests to
+//! a long-lived helper process over a pair of streams, usually the helper's
+//! stdin and stdout, and the helper answers each in turn.
+//!
+//! # Format
+//!
+//! Each message is a series of `key=value` lines, ended by a blank line. A
+//! request has an `action` (`get`, `store` or `erase`), and any of the `host`,
+//! `port`, `database`, `user` and `password` keys. Missing keys are wildcards.
+//!
+//! ```text
+//! action=get
+//! host=db.example.com
+//! database=orders
+//! user=alice
+//!
+//! ```
+//!
+//! The answer to a `get` repeats the keys with a `password`, or is empty if the
+//! helper has no credentials. The answers to `store` and `erase` are empty. If
+//! the helper fails, it answers with an `error` key instead. Unknown keys are
+//! ignored, so that the protocol can be extended.
+//!
+//! Use [`HelperClient`] to talk to a helper, and [`PgPassHelper`] to serve a
+//! [`PgPass`] as one.
+
+use std::{
+    fmt::{Debug, Display},
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use thiserror::Error;
+
+use super::{credentials_for, CredentialSource, SourceError};
+use crate::{
+    pgpass::{pattern::HasPasswordTrue, CredentialPattern, CredentialQuery, FindError},
+    Credentials, PgPass,
+};
+
+/// An operation requested of a helper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Look up the credentials for the request.
+    Get,
+    /// Save the password for the request.
+    Store,
+    /// Forget the password for the request.
+    Erase,
+}
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Get => "get",
+            Self::Store => "store",
+            Self::Erase => "erase",
+        })
+    }
+}
+impl FromStr for Action {
+    type Err = HelperError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "get" => Ok(Self::Get),
+            "store" => Ok(Self::Store),
+            "erase" => Ok(Self::Erase),
+            _ => Err(HelperError::Protocol(format!("Unknown action `{}`.", s))),
+        }
+    }
+}
+
+/// A request sent to a helper.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Request {
+    pub action: Action,
+    pub query: CredentialQuery,
+    /// Only used by [`Action::Store`].
+    pub password: Option<String>,
+}
+impl Debug for Request {
+    // Hand-rolled to censor passwords
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Request")
+            .field("action", &self.action)
+            .field("query", &self.query)
+            .field("password", &self.password.as_ref().map(|_| "********"))
+            .finish()
+    }
+}
+
+/// An error encountered while speaking the helper protocol. It is safe to log or
+/// display this error; it will not contain passwords.
+#[derive(Error, Debug)]
+pub enum HelperError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// A message was malformed.
+    #[error("Invalid helper message: {0}")]
+    Protocol(String),
+    /// A value contains a line break, so it can't be sent.
+    #[error("The value of `{0}` contains a line break.")]
+    InvalidValue(&'static str),
+    /// The helper answered with an error.
+    #[error("The credential helper failed: {0}")]
+    Remote(String),
+    /// The helper closed the connection before answering.
+    #[error("The credential helper closed the connection.")]
+    Closed,
+}
+
+/// The `key=value` pairs of one message.
+type Message = Vec<(String, String)>;
+
+/// Read a message. Returns `None` if the stream ended before it began. A
+/// malformed message is read to it's end before the error is returned, so that
+/// the next message can be read.
+fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<Message>, HelperError> {
+    let mut message = Message::new();
+    let mut malformed = false;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let ended = reader.read_line(&mut line)? == 0;
+        let line = line.trim_end_matches(['\n', '\r']);
+        if malformed && (ended || line.is_empty()) {
+            return Err(HelperError::Protocol("A line has no `=`.".to_string()));
+        }
+        if ended {
+            // As in git, the end of the stream also ends the message
+            return Ok((!message.is_empty()).then_some(message));
+        }
+        if line.is_empty() {
+            return Ok(Some(message));
+        }
+        match line.split_once('=') {
+            Some((key, value)) => message.push((key.to_string(), value.to_string())),
+            None => malformed = true,
+        }
+    }
+}
+
+fn write_message<W: Write>(
+    writer: &mut W,
+    message: &[(&'static str, &str)],
+) -> Result<(), HelperError> {
+    let mut buf = String::new();
+    for (key, value) in message {
+        if value.contains(['\n', '\r']) {
+            return Err(HelperError::InvalidValue(key));
+        }
+        buf.push_str(key);
+        buf.push('=');
+        buf.push_str(value);
+        buf.push('\n');
+    }
+    buf.push('\n');
+    writer.write_all(buf.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn get<'a>(message: &'a Message, key: &str) -> Option<&'a str> {
+    message
+        .iter()
+        .rev()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Overlay the fields of `message` on `query`.
+fn query_from(
+    message: &Message,
+    mut query: CredentialQuery,
+) -> Result<CredentialQuery, HelperError> {
+    let invalid = |e| HelperError::Protocol(format!("{}", e));
+    if let Some(host) = get(message, "host") {
+        query = query.hostname(host).map_err(invalid)?;
+    }
+    if let Some(port) = get(message, "port") {
+        let port: u16 = port
+            .parse()
+            .map_err(|_| HelperError::Protocol(format!("Invalid port `{}`.", port)))?;
+        query = query.port(port).map_err(invalid)?;
+    }
+    if let Some(database) = get(message, "database") {
+        query = query.database(database).map_err(invalid)?;
+    }
+    if let Some(user) = get(message, "user") {
+        query = query.username(user).map_err(invalid)?;
+    }
+    Ok(query)
+}
+
+fn query_fields(query: &CredentialQuery) -> Vec<(&'static str, String)> {
+    let mut fields = Vec::new();
+    if let Some(hostname) = &query.hostname {
+        fields.push(("host", hostname.clone()));
+    }
+    if let Some(port) = query.port {
+        fields.push(("port", port.to_string()));
+    }
+    if let Some(database) = &query.database {
+        fields.push(("database", database.clone()));
+    }
+    if let Some(username) = &query.username {
+        fields.push(("user", username.clone()));
+    }
+    fields
+}
+
+/// Read a request. Returns `None` if the client closed the connection.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, HelperError> {
+    let Some(message) = read_message(reader)? else {
+        return Ok(None);
+    };
+    let action = get(&message, "action")
+        .ok_or_else(|| HelperError::Protocol("The request has no action.".to_string()))?
+        .parse()?;
+    Ok(Some(Request {
+        action,
+        query: query_from(&message, CredentialQuery::default())?,
+        password: get(&message, "password").map(str::to_string),
+    }))
+}
+
+/// Write a request.
+pub fn write_request<W: Write>(writer: &mut W, request: &Request) -> Result<(), HelperError> {
+    let action = request.action.to_string();
+    let fields = query_fields(&request.query);
+    let mut message: Vec<(&'static str, &str)> = vec![("action", &action)];
+    message.extend(fields.iter().map(|(k, v)| (*k, v.as_str())));
+    if let Some(password) = &request.password {
+        message.push(("password", password));
+    }
+    write_message(writer, &message)
+}
+
+/// A client of a credential helper. Requests are sent one at a time; the client
+/// may be shared between threads.
+///
+/// ```no_run
+/// # use std::process::Command;
+/// # use postgres_secrets::{pgpass::*, source::{helper::*, *}};
+/// # fn main() -> anyhow::Result<()> {
+/// let helper = HelperClient::spawn(&mut Command::new("pg-credential-vault"))?;
+/// let query = CredentialQuery::default()
+///     .hostname("db.example.com")?
+///     .database("orders")?
+///     .username("alice")?;
+/// let creds = helper.lookup(&query)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct HelperClient<R, W> {
+    streams: Mutex<(R, W)>,
+    child: Option<Child>,
+}
+impl<R: BufRead, W: Write> HelperClient<R, W> {
+    /// Speak to a helper which reads requests from `writer` and answers on
+    /// `reader`.
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            streams: Mutex::new((reader, writer)),
+            child: None,
+        }
+    }
+    fn send(&self, request: &Request) -> Result<Message, HelperError> {
+        let mut streams = self.streams.lock().unwrap_or_else(|e| e.into_inner());
+        let (reader, writer) = &mut *streams;
+        write_request(writer, request)?;
+        let answer = read_message(reader)?.ok_or(HelperError::Closed)?;
+        if let Some(error) = get(&answer, "error") {
+            return Err(HelperError::Remote(error.to_string()));
+        }
+        Ok(answer)
+    }
+    /// Look up the credentials for `query`. Fields which the helper doesn't
+    /// answer with are taken from the query.
+    pub fn get(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        let answer = self.send(&Request {
+            action: Action::Get,
+            query: query.clone(),
+            password: None,
+        })?;
+        let Some(password) = get(&answer, "password") else {
+            return Ok(None);
+        };
+        let query = query_from(&answer, query.clone())?;
+        Ok(Some(
+            credentials_for(&query, password.to_string()).map_err(FindError::from)?,
+        ))
+    }
+    /// Ask the helper to save `pattern`.
+    pub fn store(&self, pattern: &CredentialPattern<HasPasswordTrue>) -> Result<(), HelperError> {
+        self.send(&Request {
+            action: Action::Store,
+            query: CredentialQuery {
+                hostname: pattern.hostname.clone(),
+                port: pattern.port,
+                database: pattern.database.clone(),
+                username: pattern.username.clone(),
+            },
+            password: Some(pattern.password.clone()),
+        })?;
+        Ok(())
+    }
+    /// Ask the helper to forget the password for exactly these fields.
+    pub fn erase(&self, query: &CredentialQuery) -> Result<(), HelperError> {
+        self.send(&Request {
+            action: Action::Erase,
+            query: query.clone(),
+            password: None,
+        })?;
+        Ok(())
+    }
+}
+impl HelperClient<BufReader<ChildStdout>, ChildStdin> {
+    /// Start a helper process, and speak to it over it's stdin and stdout. The
+    /// process is killed when the client is dropped.
+    pub fn spawn(command: &mut Command) -> Result<Self, io::Error> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Self {
+            streams: Mutex::new((BufReader::new(stdout), stdin)),
+            child: Some(child),
+        })
+    }
+}
+impl<R, W> Drop for HelperClient<R, W> {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+impl<R, W> Debug for HelperClient<R, W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HelperClient")
+            .field("child", &self.child.as_ref().map(Child::id))
+            .finish()
+    }
+}
+impl<R: BufRead, W: Write> CredentialSource for HelperClient<R, W> {
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        self.get(query)
+    }
+}
+
+/// Serves a [`PgPass`] as a credential helper.
+///
+/// `get` uses [`PgPass::find`]. `store` replaces any pattern with exactly the
+/// same fields, and inserts the new pattern first, so that it takes precedence.
+/// `erase` removes the pattern with exactly the same fields. Changes are only
+/// made in memory; use [`pgpass`][PgPassHelper::pgpass] to save them.
+///
+/// ```no_run
+/// # use std::io;
+/// # use postgres_secrets::{PgPass, source::helper::*};
+/// # fn main() -> anyhow::Result<()> {
+/// let mut helper = PgPassHelper::new(PgPass::load()?);
+/// helper.serve(io::stdin().lock(), io::stdout().lock())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PgPassHelper {
+    pgpass: PgPass,
+}
+impl PgPassHelper {
+    pub fn new(pgpass: PgPass) -> Self {
+        Self { pgpass }
+    }
+    pub fn pgpass(&self) -> &PgPass {
+        &self.pgpass
+    }
+    pub fn into_inner(self) -> PgPass {
+        self.pgpass
+    }
+    /// Answer requests until the client closes the connection. Malformed
+    /// requests are answered with an error, and the connection is kept open.
+    pub fn serve<R: BufRead, W: Write>(&mut self, reader: R, writer: W) -> Result<(), HelperError> {
+        serve_requests(reader, writer, |request| self.handle(request))
+    }
+    fn handle(&mut self, request: &Request) -> Reply {
+        match request.action {
+            Action::Get => get_reply(&self.pgpass, &request.query),
+            Action::Store => {
+                let pattern = stored_pattern(request)?;
+                self.pgpass.retain(|p| !same_fields(p, &request.query));
+                self.pgpass.insert(0, pattern);
+                Ok(Vec::new())
+            }
+            Action::Erase => {
+                self.pgpass.retain(|p| !same_fields(p, &request.query));
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+/// The fields to reply to a request with, or an error message.
+pub(crate) type Reply = Result<Vec<(&'static str, String)>, String>;
+
+/// Answer requests with `handle` until the client closes the connection.
+/// Malformed requests are answered with an error, and the connection is kept
+/// open.
+pub(crate) fn serve_requests<R: BufRead, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    mut handle: impl FnMut(&Request) -> Reply,
+) -> Result<(), HelperError> {
+    loop {
+        let reply = match read_request(&mut reader) {
+            Ok(Some(request)) => handle(&request),
+            Ok(None) => return Ok(()),
+            Err(HelperError::Protocol(e)) => Err(e),
+            Err(e) => return Err(e),
+        };
+        match reply {
+            Ok(fields) => {
+                let fields: Vec<_> = fields.iter().map(|(k, v)| (*k, v.as_str())).collect();
+                write_message(&mut writer, &fields)?
+            }
+            Err(e) => write_message(&mut writer, &[("error", &e)])?,
+        }
+    }
+}
+
+/// Reply to a `get` request from `pgpass`.
+pub(crate) fn get_reply(pgpass: &PgPass, query: &CredentialQuery) -> Reply {
+    match pgpass.find(query) {
+        Ok(Some(creds)) => Ok(vec![
+            ("host", creds.hostname),
+            ("port", creds.port.to_string()),
+            ("database", creds.database),
+            ("user", creds.username),
+            ("password", creds.password),
+        ]),
+        Ok(None) => Ok(Vec::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The pattern a `store` request asks to save.
+pub(crate) fn stored_pattern(
+    request: &Request,
+) -> Result<CredentialPattern<HasPasswordTrue>, String> {
+    let password = request
+        .password
+        .as_ref()
+        .ok_or_else(|| "A store request needs a password.".to_string())?;
+    let pattern = CredentialPattern::default()
+        .password(password)
+        .map_err(|e| e.to_string())?;
+    let query = &request.query;
+    Ok(CredentialPattern {
+        hostname: query.hostname.clone(),
+        port: query.port,
+        database: query.database.clone(),
+        username: query.username.clone(),
+        ..pattern
+    })
+}
+
+/// Whether `pattern` has exactly the fields of `query`, wildcards included.
+pub(crate) fn same_fields(
+    pattern: &CredentialPattern<HasPasswordTrue>,
+    query: &CredentialQuery,
+) -> bool {
+    pattern.hostname == query.hostname
+        && pattern.port == query.port
+        && pattern.database == query.database
+        && pattern.username == query.username
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::{io::Cursor, os::unix::net::UnixStream, thread};
+
+    use super::*;
+
+    fn query() -> CredentialQuery {
+        CredentialQuery::default()
+            .hostname("example.com")
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username("username")
+            .unwrap()
+    }
+
+    /// A client connected to a helper serving `pgpass` on another thread. The
+    /// helper is returned when the client is dropped.
+    fn connect(
+        pgpass: PgPass,
+    ) -> (
+        HelperClient<BufReader<UnixStream>, UnixStream>,
+        thread::JoinHandle<PgPass>,
+    ) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            let mut helper = PgPassHelper::new(pgpass);
+            helper
+                .serve(BufReader::new(server.try_clone().unwrap()), server)
+                .unwrap();
+            helper.into_inner()
+        });
+        let client = HelperClient::new(BufReader::new(client.try_clone().unwrap()), client);
+        (client, handle)
+    }
+
+    #[test]
+    fn wire_format() -> anyhow::Result<()> {
+        let request = Request {
+            action: Action::Store,
+            query: query().port(123)?,
+            password: Some("pass=word".to_string()),
+        };
+        let mut buf = Vec::new();
+        write_request(&mut buf, &request)?;
+        assert_eq!(
+            String::from_utf8(buf.clone())?,
+            "action=store\nhost=example.com\nport=123\ndatabase=database\nuser=username\npassword=pass=word\n\n"
+        );
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_request(&mut reader)?, Some(request));
+        assert_eq!(read_request(&mut reader)?, None);
+
+        // Unknown keys are ignored, and the end of the stream ends a message
+        let mut reader = Cursor::new("action=get\nprotocol=postgres\nhost=example.com");
+        let request = read_request(&mut reader)?.unwrap();
+        assert_eq!(request.query.hostname.as_deref(), Some("example.com"));
+
+        assert!(read_request(&mut Cursor::new("action=list\n\n")).is_err());
+        assert!(read_request(&mut Cursor::new("host=example.com\n\n")).is_err());
+        assert!(read_request(&mut Cursor::new("action=get\nport=http\n\n")).is_err());
+
+        let request = Request {
+            action: Action::Store,
+            query: CredentialQuery::default(),
+            password: Some("a\nb".to_string()),
+        };
+        assert!(matches!(
+            write_request(&mut Vec::new(), &request),
+            Err(HelperError::InvalidValue("password"))
+        ));
+        assert!(!format!("{:?}", request).contains("a\nb"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_store_erase() -> anyhow::Result<()> {
+        let (client, handle) = connect("*:*:*:*:fallback".parse()?);
+
+        let creds = client.get(&query())?.unwrap();
+        assert_eq!(creds.password, "fallback");
+        assert_eq!(creds.port.get(), crate::DEFAULT_PORT);
+
+        let stored = CredentialPattern::default()
+            .hostname("example.com")?
+            .database("database")?
+            .username("username")?
+            .password("stored")?;
+        client.store(&stored)?;
+        client.store(&stored.clone().password("replaced")?)?;
+        assert_eq!(client.lookup(&query())?.unwrap().password, "replaced");
+
+        client.erase(&query())?;
+        assert_eq!(client.get(&query())?.unwrap().password, "fallback");
+
+        // Errors are reported, and the connection stays usable
+        let incomplete = CredentialQuery::default().hostname("example.com")?;
+        assert!(matches!(
+            client.get(&incomplete),
+            Err(SourceError::Helper(HelperError::Remote(_)))
+        ));
+        client.erase(&CredentialQuery::default())?;
+        assert_eq!(client.get(&query())?, None);
+
+        drop(client);
+        assert!(handle.join().unwrap().find(&query())?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_requests_are_answered() -> anyhow::Result<()> {
+        let mut helper = PgPassHelper::new("*:*:*:*:secret".parse()?);
+        let mut output = Vec::new();
+        helper.serve(
+            Cursor::new("action=list\n\naction=get\nhost=h\ndatabase=d\nuser=u\n\n"),
+            &mut output,
+        )?;
+        let output = String::from_utf8(output)?;
+        let mut answers = output.split("\n\n");
+        assert_eq!(answers.next(), Some("error=Unknown action `list`."));
+        assert!(answers.next().unwrap().ends_with("password=secret"));
+
+        // The rest of a malformed request is skipped, rather than read as the next
+        let mut output = Vec::new();
+        helper.serve(
+            Cursor::new(
+                "action=get\ngarbage\nhost=h\n\naction=get\nhost=h\ndatabase=d\nuser=u\n\n",
+            ),
+            &mut output,
+        )?;
+        let output = String::from_utf8(output)?;
+        let mut answers = output.split("\n\n");
+        assert_eq!(answers.next(), Some("error=A line has no `=`."));
+        assert!(answers.next().unwrap().ends_with("password=secret"));
+        assert_eq!(answers.next(), Some(""));
+
+        // Reading from a closed helper
+        let client = HelperClient::new(Cursor::new(Vec::new()), Vec::new());
+        assert!(matches!(
+            client.get(&query()),
+            Err(SourceError::Helper(HelperError::Closed))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn spawned_helper() -> anyhow::Result<()> {
+        // A helper which answers every request with the same password
+        let script = "while read -r line; do \
+            if [ -z \"$line\" ]; then printf 'password=from-helper\\n\\n'; fi; \
+        done";
+        let helper = HelperClient::spawn(Command::new("sh").arg("-c").arg(script))?;
+        assert_eq!(helper.get(&query())?.unwrap().password, "from-helper");
+        assert_eq!(helper.get(&query())?.unwrap().password, "from-helper");
+
+        Ok(())
+    }
+}
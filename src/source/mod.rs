@@ -0,0 +1,286 @@
+//! Looking up credentials from sources other than a concrete [`PgPass`].
+//!
+//! Code which needs credentials can take an `impl` [`CredentialSource`], and be
+//! given a [`PgPass`], a [`WatchedPgPass`], a [`CommandSource`][command::CommandSource],
+//! a [`HelperClient`][helper::HelperClient], a test double, or a [`Chain`] of
+//! several sources.
+
+use std::{error::Error as StdError, fmt::Debug, num::NonZeroU16, sync::Arc};
+
+use thiserror::Error;
+
+use crate::{
+    pgpass::{watch::WatchedPgPass, CredentialQuery, FindError, IncompleteCredential, LoadError},
+    Credentials, PgPass, DEFAULT_PORT,
+};
+
+#[cfg(all(unix, feature = "agent"))]
+pub mod agent;
+pub mod command;
+pub mod helper;
+
+/// Something which can look up the [`Credentials`] for a query.
+pub trait CredentialSource {
+    /// Look up the credentials for `query`. Returns `None` if the source has no
+    /// credentials for it.
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError>;
+}
+
+/// An error encountered by a [`CredentialSource`]. It is safe to log or display
+/// this error; it will not contain passwords.
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("{0}")]
+    Find(#[from] FindError),
+    #[error("{0}")]
+    Load(#[from] LoadError),
+    #[error("{0}")]
+    Command(#[from] command::CommandError),
+    #[error("{0}")]
+    Helper(#[from] helper::HelperError),
+    /// A source in a [`Chain`] failed.
+    #[error("Credential source `{name}` failed: {source}")]
+    Chain {
+        name: String,
+        source: Box<SourceError>,
+    },
+    /// An error from a source outside of this crate.
+    #[error("{0}")]
+    Other(Box<dyn StdError + Send + Sync>),
+}
+impl SourceError {
+    pub fn other(error: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Self::Other(error.into())
+    }
+}
+
+/// Build credentials from the fields of `query`, which must all be set except
+/// for the port.
+pub(crate) fn credentials_for(
+    query: &CredentialQuery,
+    password: String,
+) -> Result<Credentials, IncompleteCredential> {
+    Ok(Credentials {
+        hostname: query
+            .hostname
+            .clone()
+            .ok_or(IncompleteCredential::MissingHostname)?,
+        port: query
+            .port
+            .unwrap_or(NonZeroU16::new(DEFAULT_PORT).expect("the default port is not 0")),
+        database: query
+            .database
+            .clone()
+            .ok_or(IncompleteCredential::MissingDatabase)?,
+        username: query
+            .username
+            .clone()
+            .ok_or(IncompleteCredential::MissingUsername)?,
+        password,
+    })
+}
+
+impl CredentialSource for PgPass {
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        Ok(self.find(query)?)
+    }
+}
+impl CredentialSource for WatchedPgPass {
+    /// Look up the credentials in the current [snapshot][WatchedPgPass::snapshot].
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        Ok(self.snapshot().find(query)?)
+    }
+}
+impl<S: CredentialSource + ?Sized> CredentialSource for &S {
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        (**self).lookup(query)
+    }
+}
+impl<S: CredentialSource + ?Sized> CredentialSource for Box<S> {
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        (**self).lookup(query)
+    }
+}
+impl<S: CredentialSource + ?Sized> CredentialSource for Arc<S> {
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        (**self).lookup(query)
+    }
+}
+
+/// Tries several sources in order, and answers with the first which has
+/// credentials for the query.
+///
+/// If a source fails, the error is returned rather than trying the next source.
+/// Falling through would silently use a lower-precedence source, for example
+/// because a secret store was briefly unavailable.
+///
+/// ```
+/// # use postgres_secrets::{pgpass::*, source::*};
+/// # fn main() -> anyhow::Result<()> {
+/// let overrides: PgPass = "db.example.com:*:*:alice:override".parse()?;
+/// let defaults: PgPass = "*:*:*:*:default".parse()?;
+/// let chain = Chain::default()
+///     .with("overrides", overrides)
+///     .with("defaults", defaults);
+///
+/// let query = CredentialQuery::default()
+///     .hostname("db.example.com")?
+///     .database("orders")?
+///     .username("bob")?;
+/// let answer = chain.lookup_traced(&query)?.unwrap();
+/// assert_eq!(answer.name, "defaults");
+/// assert_eq!(answer.credentials.password, "default");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Chain {
+    sources: Vec<(String, Box<dyn CredentialSource + Send + Sync>)>,
+}
+impl Chain {
+    /// Add a source to the chain. Sources are tried in order, so this new
+    /// source will have the lowest precedence. `name` identifies the source in
+    /// answers and errors.
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        source: impl CredentialSource + Send + Sync + 'static,
+    ) {
+        self.sources.push((name.into(), Box::new(source)));
+    }
+    /// Builder interface to [`add`][Chain::add].
+    pub fn with(
+        mut self,
+        name: impl Into<String>,
+        source: impl CredentialSource + Send + Sync + 'static,
+    ) -> Self {
+        self.add(name, source);
+        self
+    }
+    /// The names of the sources, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sources.iter().map(|(name, _)| name.as_str())
+    }
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+    /// The same as [`lookup`][CredentialSource::lookup], but also returns which
+    /// source answered.
+    pub fn lookup_traced(
+        &self,
+        query: &CredentialQuery,
+    ) -> Result<Option<Answer<'_>>, SourceError> {
+        for (index, (name, source)) in self.sources.iter().enumerate() {
+            let found = source.lookup(query).map_err(|e| SourceError::Chain {
+                name: name.clone(),
+                source: Box::new(e),
+            })?;
+            if let Some(credentials) = found {
+                return Ok(Some(Answer {
+                    credentials,
+                    index,
+                    name,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+impl CredentialSource for Chain {
+    fn lookup(&self, query: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+        Ok(self.lookup_traced(query)?.map(|answer| answer.credentials))
+    }
+}
+impl Debug for Chain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Chain")
+            .field("sources", &self.names().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// The credentials found by a [`Chain`], and the source which found them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answer<'a> {
+    pub credentials: Credentials,
+    /// The position of the source in the chain.
+    pub index: usize,
+    /// The name the source was added with.
+    pub name: &'a str,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A source which always fails.
+    struct Broken;
+    impl CredentialSource for Broken {
+        fn lookup(&self, _: &CredentialQuery) -> Result<Option<Credentials>, SourceError> {
+            Err(SourceError::other("unavailable"))
+        }
+    }
+
+    fn query(username: &str) -> CredentialQuery {
+        CredentialQuery::default()
+            .hostname("example.com")
+            .unwrap()
+            .database("database")
+            .unwrap()
+            .username(username)
+            .unwrap()
+    }
+
+    #[test]
+    fn pgpass() -> anyhow::Result<()> {
+        let pgpass: PgPass = "*:*:*:alice:secret".parse()?;
+        assert_eq!(pgpass.lookup(&query("alice"))?.unwrap().password, "secret");
+        assert_eq!(pgpass.lookup(&query("bob"))?, None);
+
+        // Through a reference and a trait object
+        let source: Box<dyn CredentialSource> = Box::new(&pgpass);
+        assert!(source.lookup(&query("alice"))?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn chain_uses_first_answer() -> anyhow::Result<()> {
+        let chain = Chain::default()
+            .with("first", "*:*:*:alice:one".parse::<PgPass>()?)
+            .with("second", "*:*:*:*:two".parse::<PgPass>()?);
+        assert_eq!(chain.names().collect::<Vec<_>>(), ["first", "second"]);
+
+        let answer = chain.lookup_traced(&query("alice"))?.unwrap();
+        assert_eq!((answer.index, answer.name), (0, "first"));
+        assert_eq!(answer.credentials.password, "one");
+
+        let answer = chain.lookup_traced(&query("bob"))?.unwrap();
+        assert_eq!((answer.index, answer.name), (1, "second"));
+        assert_eq!(chain.lookup(&query("bob"))?.unwrap().password, "two");
+
+        assert_eq!(Chain::default().lookup(&query("bob"))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chain_stops_at_errors() -> anyhow::Result<()> {
+        let chain = Chain::default()
+            .with("first", "*:*:*:alice:one".parse::<PgPass>()?)
+            .with("broken", Broken)
+            .with("last", "*:*:*:*:two".parse::<PgPass>()?);
+
+        // Sources after the answer aren't consulted
+        assert!(chain.lookup(&query("alice"))?.is_some());
+        match chain.lookup(&query("bob")) {
+            Err(SourceError::Chain { name, .. }) => assert_eq!(name, "broken"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        Ok(())
+    }
+}
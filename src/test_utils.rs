@@ -0,0 +1,24 @@
+//! Utilities shared by unit tests.
+
+use std::{fs, path::PathBuf};
+
+pub struct TempFile(pub PathBuf);
+impl TempFile {
+    pub fn new(name: &str, contents: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("pgpass-{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        Self(path)
+    }
+    /// Replace the file atomically, as a secret manager would. Writing it
+    /// in place could let the watcher observe a partially written file.
+    pub fn replace(&self, contents: &str) {
+        let tmp = self.0.with_extension("tmp");
+        fs::write(&tmp, contents).unwrap();
+        fs::rename(&tmp, &self.0).unwrap();
+    }
+}
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}